@@ -0,0 +1,203 @@
+// Local websocket access to the backdoor server, so companion tools can drive devices while something
+// else holds the main client connection. Only listens on localhost, and every connection has to
+// present the configured token as `Authorization: Bearer <token>` during the websocket handshake.
+//
+// Messages are proxied as is, so clients speak the same JSON as they would to the backdoor in
+// process, handshake included. Every connection gets its own BackdoorSession, so clients only see
+// their own replies. Clients can restrict themselves to a narrower BackdoorScope than
+// admin by sending `X-Intiface-Backdoor-Scope: <scope>`, so a monitoring tool can't drive devices by
+// mistake.
+
+use super::{BackdoorScope, BackdoorServer, BackdoorServerError, DeniedMessage};
+use crate::IntifaceError;
+use futures::{SinkExt, StreamExt};
+use std::{net::SocketAddr, sync::Arc};
+use tokio::{
+  net::{TcpListener, TcpStream},
+  select,
+};
+use tokio_tungstenite::tungstenite::{
+  handshake::server::{ErrorResponse, Request, Response},
+  http::StatusCode,
+  Message,
+};
+use tokio_util::sync::CancellationToken;
+
+// Compares without bailing out at the first mismatch, so response timing doesn't leak the token.
+fn token_matches(given: &str, expected: &str) -> bool {
+  given.len() == expected.len()
+    && given
+      .bytes()
+      .zip(expected.bytes())
+      .fold(0, |acc, (a, b)| acc | (a ^ b))
+      == 0
+}
+
+const SCOPE_HEADER: &str = "X-Intiface-Backdoor-Scope";
+
+fn error_response(status: StatusCode, reason: &str) -> ErrorResponse {
+  let mut response = ErrorResponse::new(Some(reason.to_owned()));
+  *response.status_mut() = status;
+  response
+}
+
+// Buttplug error replies are sent straight back to the client that caused them, since they're not
+// coming from the server. Each denied message gets its own, so clients waiting on its id hear back.
+fn permission_denied_reply(denied: &[DeniedMessage], scope: BackdoorScope) -> String {
+  let errors: Vec<_> = denied
+    .iter()
+    .map(|denied| {
+      serde_json::json!({
+        "Error": {
+          "Id": denied.id(),
+          // ErrorCode::ErrorMessage
+          "ErrorCode": 3,
+          "ErrorMessage": format!("{} is not allowed with {} backdoor access", denied, scope),
+        }
+      })
+    })
+    .collect();
+  serde_json::Value::Array(errors).to_string()
+}
+
+fn is_authorized(request: &Request, token: &str) -> bool {
+  request
+    .headers()
+    .get("Authorization")
+    .and_then(|value| value.to_str().ok())
+    .and_then(|value| value.strip_prefix("Bearer "))
+    .is_some_and(|given| token_matches(given.trim(), token))
+}
+
+pub(crate) struct BackdoorWebsocketServer {
+  listener: TcpListener,
+  token: Arc<String>,
+}
+
+impl BackdoorWebsocketServer {
+  pub(crate) async fn bind(port: u16, token: &str) -> Result<Self, IntifaceError> {
+    let addr = format!("127.0.0.1:{}", port);
+    let listener = TcpListener::bind(&addr).await.map_err(|e| {
+      IntifaceError::new(&format!(
+        "Cannot bind backdoor websocket to {}: {:?}",
+        addr, e
+      ))
+    })?;
+    info!("Backdoor websocket listening on: {}", addr);
+    Ok(Self {
+      listener,
+      token: Arc::new(token.to_owned()),
+    })
+  }
+
+  pub(crate) async fn run(self, backdoor: Arc<BackdoorServer>, stop_token: CancellationToken) {
+    loop {
+      select! {
+        stream_result = self.listener.accept() => match stream_result {
+          Ok((stream, client_addr)) => {
+            let backdoor = backdoor.clone();
+            let token = self.token.clone();
+            let stop_token = stop_token.child_token();
+            tokio::spawn(async move {
+              accept_connection(stream, client_addr, backdoor, &token, stop_token).await;
+            });
+          }
+          Err(e) => {
+            error!("Error accepting new backdoor websocket connection: {:?}", e);
+            break;
+          }
+        },
+        _ = stop_token.cancelled() => break,
+      }
+    }
+    info!("Backdoor websocket exiting");
+  }
+}
+
+async fn accept_connection(
+  stream: TcpStream,
+  client_addr: SocketAddr,
+  backdoor: Arc<BackdoorServer>,
+  token: &str,
+  stop_token: CancellationToken,
+) {
+  let mut scope = BackdoorScope::Admin;
+  // The error response type is set by tungstenite, we can't box it.
+  #[allow(clippy::result_large_err)]
+  let check_request = |request: &Request, response: Response| {
+    if !is_authorized(request, token) {
+      return Err(error_response(StatusCode::UNAUTHORIZED, "Unauthorized"));
+    }
+    if let Some(value) = request.headers().get(SCOPE_HEADER) {
+      scope = value
+        .to_str()
+        .ok()
+        .and_then(|value| value.parse().ok())
+        .ok_or_else(|| error_response(StatusCode::BAD_REQUEST, "Invalid backdoor scope"))?;
+    }
+    Ok(response)
+  };
+  let ws_stream = match tokio_tungstenite::accept_hdr_async(stream, check_request).await {
+    Ok(ws_stream) => ws_stream,
+    Err(e) => {
+      warn!(
+        "Backdoor websocket connection from {} rejected: {}",
+        client_addr, e
+      );
+      return;
+    }
+  };
+  info!(
+    "Backdoor websocket client connected: {} ({})",
+    client_addr, scope
+  );
+
+  let session = match backdoor.session(scope) {
+    Ok(session) => session,
+    Err(e) => {
+      error!(
+        "Cannot create backdoor session for websocket client {}: {}",
+        client_addr, e
+      );
+      return;
+    }
+  };
+  let (mut write, mut read) = ws_stream.split();
+  let events = session.event_stream();
+  futures::pin_mut!(events);
+  loop {
+    select! {
+      msg = read.next() => match msg {
+        Some(Ok(Message::Text(text))) => match session.parse_message(text.as_str()).await {
+          Ok(_) => {}
+          Err(ref e @ BackdoorServerError::PermissionDenied { ref denied, scope }) => {
+            warn!("Backdoor websocket client {}: {}", client_addr, e);
+            let reply = permission_denied_reply(denied, scope);
+            if write.send(Message::Text(reply.into())).await.is_err() {
+              break;
+            }
+          }
+          Err(e) => {
+            error!("Cannot forward message to backdoor server: {}", e);
+            break;
+          }
+        },
+        Some(Ok(Message::Close(_))) | Some(Err(_)) | None => break,
+        Some(Ok(_)) => continue,
+      },
+      event = events.next() => match event {
+        Some(event) => {
+          if write.send(Message::Text(event.into())).await.is_err() {
+            break;
+          }
+        }
+        None => break,
+      },
+      _ = stop_token.cancelled() => {
+        let _ = write.send(Message::Close(None)).await;
+        break;
+      }
+    }
+  }
+  info!("Backdoor websocket client disconnected: {}", client_addr);
+}
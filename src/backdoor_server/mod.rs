@@ -0,0 +1,204 @@
+mod ipc;
+mod scope;
+mod session;
+
+pub(crate) use ipc::BackdoorWebsocketServer;
+pub use scope::{BackdoorScope, DeniedMessage};
+pub use session::BackdoorSession;
+
+use buttplug::{
+  core::{
+    errors::ButtplugError,
+    message::{ButtplugClientMessageV3, ButtplugServerMessageV3, ScalarSubcommandV3, SensorType},
+  },
+  server::{device::ServerDeviceManager, ButtplugServerError},
+};
+use std::{sync::Arc, time::Duration};
+use thiserror::Error;
+use tokio_stream::Stream;
+
+const DEFAULT_BACKDOOR_REQUEST_TIMEOUT: Duration = Duration::from_secs(10);
+
+#[derive(Error, Debug)]
+pub enum BackdoorServerError {
+  #[error("Backdoor request timed out after {0:?}")]
+  Timeout(Duration),
+  #[error("Backdoor request failed: {0}")]
+  ButtplugError(ButtplugError),
+  #[error("Backdoor server replied with an unexpected message version")]
+  UnexpectedResponse,
+  #[error("Backdoor server is not running")]
+  NotRunning,
+  #[error("Cannot create backdoor server: {0}")]
+  ServerSetup(ButtplugServerError),
+  #[error(
+    "Not allowed with {scope} backdoor access: {}",
+    denied.iter().map(|d| d.to_string()).collect::<Vec<_>>().join(", ")
+  )]
+  PermissionDenied {
+    denied: Vec<DeniedMessage>,
+    scope: BackdoorScope,
+  },
+}
+
+// Allows direct access to the Device Manager of a running ButtplugServer. Bypasses requirements for
+// client handshake, ping, etc...
+//
+// There are two ways in. parse_message()/event_stream() take and produce raw JSON, exactly like a
+// websocket client would. The typed methods (send_message(), device_list(), etc...) go through a
+// separate server on the same device manager, which handshakes itself, so they don't interfere with
+// whatever is using the JSON side.
+//
+// Everything here goes through a default admin session that all users of the BackdoorServer share.
+// Anything that wants its replies to itself should get its own from session().
+pub struct BackdoorServer {
+  device_manager: Arc<ServerDeviceManager>,
+  session: BackdoorSession,
+}
+
+/// What to do when the backdoor's internal server stops unexpectedly.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BackdoorRestartPolicy {
+  /// Leave it stopped. [BackdoorServer::parse_message] fails from then on.
+  Never,
+  /// Restart it after `delay`, up to `max_restarts` times (or forever, if None).
+  OnFailure {
+    max_restarts: Option<u32>,
+    delay: Duration,
+  },
+}
+
+impl Default for BackdoorRestartPolicy {
+  fn default() -> Self {
+    BackdoorRestartPolicy::OnFailure {
+      max_restarts: Some(5),
+      delay: Duration::from_secs(1),
+    }
+  }
+}
+
+impl BackdoorServer {
+  pub fn new(device_manager: Arc<ServerDeviceManager>) -> Result<Self, BackdoorServerError> {
+    let session = BackdoorSession::new(
+      device_manager.clone(),
+      BackdoorScope::Admin,
+      BackdoorRestartPolicy::default(),
+      DEFAULT_BACKDOOR_REQUEST_TIMEOUT,
+    )?;
+    Ok(Self {
+      device_manager,
+      session,
+    })
+  }
+
+  /// Creates a new session on the backdoor's device manager. Sessions handshake separately, have
+  /// their own message ids, and only get their own replies on their event stream. They start out
+  /// with the current restart policy and request timeout of this server. Fails with
+  /// [BackdoorServerError::ServerSetup] if the session's server can't be created.
+  pub fn session(&self, scope: BackdoorScope) -> Result<BackdoorSession, BackdoorServerError> {
+    BackdoorSession::new(
+      self.device_manager.clone(),
+      scope,
+      self.restart_policy(),
+      self.request_timeout(),
+    )
+  }
+
+  pub fn event_stream(&self) -> impl Stream<Item = String> + '_ {
+    self.session.event_stream()
+  }
+
+  /// True if the JSON side of the backdoor is currently running. While it's being restarted, or if
+  /// the restart policy has given up on it, this is false.
+  pub fn is_alive(&self) -> bool {
+    self.session.is_alive()
+  }
+
+  pub fn restart_policy(&self) -> BackdoorRestartPolicy {
+    self.session.restart_policy()
+  }
+
+  pub fn set_restart_policy(&self, policy: BackdoorRestartPolicy) {
+    self.session.set_restart_policy(policy)
+  }
+
+  /// Sends a raw JSON message to the backdoor. Replies show up on [BackdoorServer::event_stream].
+  /// Fails if the backdoor has stopped for good (see [BackdoorRestartPolicy]). Messages sent while
+  /// it's restarting are delivered once it's back up.
+  pub async fn parse_message(&self, msg: &str) -> Result<(), BackdoorServerError> {
+    self.session.parse_message(msg).await
+  }
+
+  /// Like [BackdoorServer::parse_message], but only forwards the messages that `scope` allows. The
+  /// rest are listed in a [BackdoorServerError::PermissionDenied], and won't get a reply from the
+  /// server.
+  pub async fn parse_scoped_message(
+    &self,
+    msg: &str,
+    scope: BackdoorScope,
+  ) -> Result<(), BackdoorServerError> {
+    let (allowed, denied) = scope.filter_json_messages(msg);
+    if let Some(allowed) = allowed {
+      self.parse_message(&allowed).await?;
+    }
+    if denied.is_empty() {
+      Ok(())
+    } else {
+      Err(BackdoorServerError::PermissionDenied { denied, scope })
+    }
+  }
+
+  pub fn request_timeout(&self) -> Duration {
+    self.session.request_timeout()
+  }
+
+  /// Sets how long typed requests wait for a reply before failing with
+  /// [BackdoorServerError::Timeout]. Defaults to 10 seconds.
+  pub fn set_request_timeout(&self, timeout: Duration) {
+    self.session.set_request_timeout(timeout)
+  }
+
+  /// Sends a message to the device manager and waits for its reply. The message id is filled in, and
+  /// errors from the server come back as [BackdoorServerError::ButtplugError].
+  pub async fn send_message(
+    &self,
+    msg: ButtplugClientMessageV3,
+  ) -> Result<ButtplugServerMessageV3, BackdoorServerError> {
+    self.session.send_message(msg).await
+  }
+
+  pub async fn device_list(&self) -> Result<ButtplugServerMessageV3, BackdoorServerError> {
+    self.session.device_list().await
+  }
+
+  pub async fn send_scalar(
+    &self,
+    device_index: u32,
+    scalars: Vec<ScalarSubcommandV3>,
+  ) -> Result<ButtplugServerMessageV3, BackdoorServerError> {
+    self.session.send_scalar(device_index, scalars).await
+  }
+
+  pub async fn stop_device(
+    &self,
+    device_index: u32,
+  ) -> Result<ButtplugServerMessageV3, BackdoorServerError> {
+    self.session.stop_device(device_index).await
+  }
+
+  pub async fn stop_all(&self) -> Result<ButtplugServerMessageV3, BackdoorServerError> {
+    self.session.stop_all().await
+  }
+
+  pub async fn read_sensor(
+    &self,
+    device_index: u32,
+    sensor_index: u32,
+    sensor_type: SensorType,
+  ) -> Result<ButtplugServerMessageV3, BackdoorServerError> {
+    self
+      .session
+      .read_sensor(device_index, sensor_index, sensor_type)
+      .await
+  }
+}
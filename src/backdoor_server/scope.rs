@@ -0,0 +1,325 @@
+// Backdoor permission scopes. The backdoor skips everything a regular client connection would have to
+// go through, so access can be narrowed down by message type instead, before anything reaches the
+// wrapped server.
+
+use super::BackdoorServerError;
+use crate::IntifaceError;
+use buttplug::core::message::ButtplugClientMessageV3;
+use getset::{CopyGetters, Getters};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::{fmt, str::FromStr};
+
+/// A message that was held back because the backdoor scope doesn't allow it.
+#[derive(Debug, Clone, PartialEq, Eq, Getters, CopyGetters)]
+pub struct DeniedMessage {
+  #[getset(get = "pub")]
+  message_type: String,
+  #[getset(get_copy = "pub")]
+  id: u32,
+}
+
+impl fmt::Display for DeniedMessage {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    write!(f, "{} (id {})", self.message_type, self.id)
+  }
+}
+
+/// What a backdoor user is allowed to do. Scopes are ordered, each one allows everything the ones
+/// before it do.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum BackdoorScope {
+  /// Handshake, device list, battery and sensor reads.
+  ReadOnly,
+  /// Scanning, and driving and stopping devices.
+  Actuate,
+  /// Everything, including raw commands.
+  Admin,
+}
+
+impl BackdoorScope {
+  fn name(&self) -> &'static str {
+    match self {
+      BackdoorScope::ReadOnly => "read-only",
+      BackdoorScope::Actuate => "actuate",
+      BackdoorScope::Admin => "admin",
+    }
+  }
+
+  /// Scope needed to send a message, by its Buttplug message type name (across all spec versions).
+  /// Anything we don't know about needs admin.
+  pub fn required_for(message_type: &str) -> BackdoorScope {
+    match message_type {
+      "RequestServerInfo"
+      | "Ping"
+      | "Test"
+      | "RequestDeviceList"
+      | "SensorReadCmd"
+      | "SensorSubscribeCmd"
+      | "SensorUnsubscribeCmd"
+      | "BatteryLevelCmd"
+      | "RSSILevelCmd" => BackdoorScope::ReadOnly,
+      "StartScanning"
+      | "StopScanning"
+      | "StopDeviceCmd"
+      | "StopAllDevices"
+      | "ScalarCmd"
+      | "LinearCmd"
+      | "RotateCmd"
+      | "VibrateCmd"
+      | "SingleMotorVibrateCmd"
+      | "KiirooCmd"
+      | "FleshlightLaunchFW12Cmd"
+      | "LovenseCmd"
+      | "VorzeA10CycloneCmd" => BackdoorScope::Actuate,
+      _ => BackdoorScope::Admin,
+    }
+  }
+
+  pub fn permits(&self, message_type: &str) -> bool {
+    *self >= BackdoorScope::required_for(message_type)
+  }
+
+  // Messages serialize as `{ "<MessageType>": { "Id": ..., ... } }`. Anything else isn't a message
+  // the server could act on, so it's left for the server to reject.
+  fn check_json_message(&self, message: &Value) -> Option<DeniedMessage> {
+    let (message_type, body) = message.as_object()?.iter().next()?;
+    if self.permits(message_type) {
+      return None;
+    }
+    Some(DeniedMessage {
+      message_type: message_type.clone(),
+      // Messages without an id are rejected by the server with id 0 too.
+      id: body
+        .get("Id")
+        .and_then(|id| id.as_u64())
+        .unwrap_or_default() as u32,
+    })
+  }
+
+  /// Splits a JSON message array into the part this scope allows (None if that's nothing), and the
+  /// messages it doesn't. Input that isn't a JSON array is passed on as is, so the server handles it
+  /// the same way it would for any other client.
+  pub fn filter_json_messages(&self, msg: &str) -> (Option<String>, Vec<DeniedMessage>) {
+    if *self == BackdoorScope::Admin {
+      return (Some(msg.to_owned()), vec![]);
+    }
+    let Ok(Value::Array(messages)) = serde_json::from_str(msg) else {
+      return (Some(msg.to_owned()), vec![]);
+    };
+    let mut denied = vec![];
+    let allowed: Vec<Value> = messages
+      .into_iter()
+      .filter(|message| match self.check_json_message(message) {
+        Some(denial) => {
+          denied.push(denial);
+          false
+        }
+        None => true,
+      })
+      .collect();
+    let allowed = if denied.is_empty() {
+      Some(msg.to_owned())
+    } else if allowed.is_empty() {
+      None
+    } else {
+      Some(Value::Array(allowed).to_string())
+    };
+    (allowed, denied)
+  }
+
+  /// Checks a typed message against this scope.
+  pub fn check_message(&self, msg: &ButtplugClientMessageV3) -> Result<(), BackdoorServerError> {
+    if *self == BackdoorScope::Admin {
+      return Ok(());
+    }
+    let denied = serde_json::to_value(msg)
+      .ok()
+      .and_then(|message| self.check_json_message(&message));
+    match denied {
+      Some(denied) => Err(BackdoorServerError::PermissionDenied {
+        denied: vec![denied],
+        scope: *self,
+      }),
+      None => Ok(()),
+    }
+  }
+}
+
+impl fmt::Display for BackdoorScope {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    write!(f, "{}", self.name())
+  }
+}
+
+impl FromStr for BackdoorScope {
+  type Err = IntifaceError;
+
+  fn from_str(s: &str) -> Result<Self, Self::Err> {
+    [
+      BackdoorScope::ReadOnly,
+      BackdoorScope::Actuate,
+      BackdoorScope::Admin,
+    ]
+    .into_iter()
+    .find(|scope| scope.name() == s)
+    .ok_or_else(|| IntifaceError::new(&format!("Unknown backdoor scope {}", s)))
+  }
+}
+
+#[cfg(test)]
+mod test {
+  use super::*;
+  use buttplug::core::message::{ButtplugMessage, RequestDeviceListV0, StopAllDevicesV0};
+
+  const SCOPES: [BackdoorScope; 3] = [
+    BackdoorScope::ReadOnly,
+    BackdoorScope::Actuate,
+    BackdoorScope::Admin,
+  ];
+
+  fn denied(message_type: &str, id: u32) -> DeniedMessage {
+    DeniedMessage {
+      message_type: message_type.to_owned(),
+      id,
+    }
+  }
+
+  #[test]
+  fn test_required_for() {
+    for message_type in ["RequestServerInfo", "RequestDeviceList", "SensorReadCmd"] {
+      assert_eq!(
+        BackdoorScope::required_for(message_type),
+        BackdoorScope::ReadOnly
+      );
+    }
+    for message_type in ["StartScanning", "ScalarCmd", "StopAllDevices", "VibrateCmd"] {
+      assert_eq!(
+        BackdoorScope::required_for(message_type),
+        BackdoorScope::Actuate
+      );
+    }
+    for message_type in ["RawWriteCmd", "RawSubscribeCmd", "NotARealMessage", ""] {
+      assert_eq!(
+        BackdoorScope::required_for(message_type),
+        BackdoorScope::Admin
+      );
+    }
+  }
+
+  #[test]
+  fn test_permits() {
+    let cases = [
+      ("RequestDeviceList", [true, true, true]),
+      ("ScalarCmd", [false, true, true]),
+      ("RawWriteCmd", [false, false, true]),
+      ("NotARealMessage", [false, false, true]),
+    ];
+    for (message_type, permitted) in cases {
+      for (scope, permitted) in SCOPES.iter().zip(permitted) {
+        assert_eq!(
+          scope.permits(message_type),
+          permitted,
+          "{} with {}",
+          message_type,
+          scope
+        );
+      }
+    }
+  }
+
+  #[test]
+  fn test_filter_mixed_array() {
+    let msg = r#"[
+      { "RequestDeviceList": { "Id": 1 } },
+      { "ScalarCmd": { "Id": 2, "DeviceIndex": 0, "Scalars": [] } },
+      { "RawWriteCmd": { "Id": 3, "DeviceIndex": 0, "Endpoint": "tx", "Data": [], "WriteWithResponse": false } }
+    ]"#;
+
+    let (allowed, denials) = BackdoorScope::ReadOnly.filter_json_messages(msg);
+    let allowed: Value = serde_json::from_str(&allowed.unwrap()).unwrap();
+    assert_eq!(
+      allowed,
+      serde_json::json!([{ "RequestDeviceList": { "Id": 1 } }])
+    );
+    assert_eq!(
+      denials,
+      vec![denied("ScalarCmd", 2), denied("RawWriteCmd", 3)]
+    );
+
+    let (allowed, denials) = BackdoorScope::Actuate.filter_json_messages(msg);
+    let allowed: Value = serde_json::from_str(&allowed.unwrap()).unwrap();
+    assert_eq!(allowed.as_array().unwrap().len(), 2);
+    assert!(allowed[1].get("ScalarCmd").is_some());
+    assert_eq!(denials, vec![denied("RawWriteCmd", 3)]);
+
+    assert_eq!(
+      BackdoorScope::Admin.filter_json_messages(msg),
+      (Some(msg.to_owned()), vec![])
+    );
+  }
+
+  #[test]
+  fn test_filter_all_allowed_is_unchanged() {
+    let msg =
+      r#"[{ "RequestServerInfo": { "Id": 1, "ClientName": "Test", "MessageVersion": 3 } }]"#;
+    assert_eq!(
+      BackdoorScope::ReadOnly.filter_json_messages(msg),
+      (Some(msg.to_owned()), vec![])
+    );
+  }
+
+  #[test]
+  fn test_filter_all_denied() {
+    let msg = r#"[{ "StartScanning": { "Id": 4 } }, { "FutureMessage": { "Id": 5 } }]"#;
+    assert_eq!(
+      BackdoorScope::ReadOnly.filter_json_messages(msg),
+      (
+        None,
+        vec![denied("StartScanning", 4), denied("FutureMessage", 5)]
+      )
+    );
+  }
+
+  #[test]
+  fn test_filter_unparseable_passed_on() {
+    for msg in ["not json", r#"{ "ScalarCmd": { "Id": 1 } }"#, "[1, 2]"] {
+      assert_eq!(
+        BackdoorScope::ReadOnly.filter_json_messages(msg),
+        (Some(msg.to_owned()), vec![]),
+        "{}",
+        msg
+      );
+    }
+  }
+
+  #[test]
+  fn test_check_message() {
+    let mut stop_all: ButtplugClientMessageV3 = StopAllDevicesV0::default().into();
+    stop_all.set_id(7);
+    let device_list: ButtplugClientMessageV3 = RequestDeviceListV0::default().into();
+
+    assert!(BackdoorScope::ReadOnly.check_message(&device_list).is_ok());
+    match BackdoorScope::ReadOnly.check_message(&stop_all) {
+      Err(BackdoorServerError::PermissionDenied { denied: d, scope }) => {
+        assert_eq!(d, vec![denied("StopAllDevices", 7)]);
+        assert_eq!(scope, BackdoorScope::ReadOnly);
+      }
+      other => panic!("Expected permission denied, got {:?}", other),
+    }
+    assert!(BackdoorScope::Actuate.check_message(&stop_all).is_ok());
+  }
+
+  #[test]
+  fn test_permission_denied_display() {
+    let error = BackdoorServerError::PermissionDenied {
+      denied: vec![denied("ScalarCmd", 2), denied("RawWriteCmd", 3)],
+      scope: BackdoorScope::ReadOnly,
+    };
+    assert_eq!(
+      error.to_string(),
+      "Not allowed with read-only backdoor access: ScalarCmd (id 2), RawWriteCmd (id 3)"
+    );
+  }
+}
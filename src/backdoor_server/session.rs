@@ -0,0 +1,337 @@
+// A single backdoor session. Each session runs its own servers on the shared device manager, so it
+// has its own handshake, message ids and replies, and can't see what other sessions are up to. Device
+// events still show up everywhere, since they come from the device manager.
+
+use super::{BackdoorRestartPolicy, BackdoorScope, BackdoorServerError};
+use buttplug::{
+  core::{
+    connector::{transport::ButtplugStreamTransport, ButtplugRemoteServerConnector},
+    message::{
+      serializer::{ButtplugSerializedMessage, ButtplugServerJSONSerializer},
+      ButtplugClientMessageV3, ButtplugClientMessageVariant, ButtplugMessage,
+      ButtplugServerMessageV3, ButtplugServerMessageVariant, RequestDeviceListV0,
+      RequestServerInfoV1, ScalarCmdV3, ScalarSubcommandV3, SensorReadCmdV3, SensorType,
+      StopAllDevicesV0, StopDeviceCmdV0, BUTTPLUG_CURRENT_MESSAGE_SPEC_VERSION,
+    },
+  },
+  server::{
+    device::ServerDeviceManager, ButtplugServer, ButtplugServerBuilder,
+    ButtplugServerDowngradeWrapper,
+  },
+  util::stream::convert_broadcast_receiver_to_stream,
+};
+use std::{
+  sync::{
+    atomic::{AtomicBool, AtomicU32, AtomicU64, Ordering},
+    Arc, Mutex,
+  },
+  time::Duration,
+};
+use tokio::{
+  select,
+  sync::{
+    broadcast,
+    mpsc::{self, Sender},
+    OnceCell,
+  },
+};
+use tokio_stream::Stream;
+
+use crate::ButtplugRemoteServer;
+
+fn backdoor_server(
+  device_manager: &Arc<ServerDeviceManager>,
+) -> Result<ButtplugServer, BackdoorServerError> {
+  ButtplugServerBuilder::with_shared_device_manager(device_manager.clone())
+    .name("Intiface Backdoor Server")
+    .finish()
+    .map_err(BackdoorServerError::ServerSetup)
+}
+
+// Runs the JSON side of a session, restarting the remote server according to the restart policy if
+// it stops while the session is still around. Restarts disconnect the server, so clients need to
+// handshake again afterward.
+async fn run_backdoor_server(
+  server: ButtplugRemoteServer,
+  mut message_receiver: mpsc::Receiver<ButtplugSerializedMessage>,
+  broadcaster: broadcast::Sender<String>,
+  alive: Arc<AtomicBool>,
+  restart_policy: Arc<Mutex<BackdoorRestartPolicy>>,
+) {
+  let mut restarts = 0;
+  loop {
+    let (s_out, mut r_out) = mpsc::channel(255);
+    let (s_in, r_in) = mpsc::channel(255);
+    let mut server_task =
+      tokio::spawn(server.start(ButtplugRemoteServerConnector::<
+        _,
+        ButtplugServerJSONSerializer,
+      >::new(ButtplugStreamTransport::new(s_out, r_in))));
+    alive.store(true, Ordering::Relaxed);
+    let result = loop {
+      select! {
+        msg = message_receiver.recv() => match msg {
+          Some(msg) => {
+            if s_in.send(msg).await.is_err() {
+              warn!("Backdoor server not accepting messages, dropping message.");
+            }
+          }
+          // The session is gone, so there's nobody left to serve.
+          None => {
+            server_task.abort();
+            return;
+          }
+        },
+        Some(msg) = r_out.recv() => {
+          if let ButtplugSerializedMessage::Text(m) = msg {
+            if broadcaster.receiver_count() > 0 {
+              let _ = broadcaster.send(m);
+            }
+          }
+        }
+        result = &mut server_task => break result,
+      }
+    };
+    alive.store(false, Ordering::Relaxed);
+    match result {
+      Ok(Ok(_)) => error!("Backdoor server stopped unexpectedly."),
+      // We can't do much if the server fails, but we *can* yell into the logs!
+      Ok(Err(e)) => error!("Backdoor server error: {:?}", e),
+      Err(e) => error!("Backdoor server task failed: {:?}", e),
+    }
+    if let Err(e) = server.disconnect().await {
+      error!("Error disconnecting backdoor server: {:?}", e);
+    }
+    let policy = *restart_policy.lock().unwrap();
+    match policy {
+      BackdoorRestartPolicy::OnFailure {
+        max_restarts,
+        delay,
+      } if max_restarts.is_none_or(|max| restarts < max) => {
+        restarts += 1;
+        info!(
+          "Restarting backdoor server in {:?} (restart {})",
+          delay, restarts
+        );
+        tokio::time::sleep(delay).await;
+      }
+      _ => {
+        error!("Backdoor server is not being restarted, backdoor access is unavailable.");
+        return;
+      }
+    }
+  }
+}
+
+/// An isolated connection to the backdoor, created with
+/// [BackdoorServer::session](super::BackdoorServer::session). Everything sent through a session is
+/// limited to its [BackdoorScope]. Dropping the session shuts down its servers.
+pub struct BackdoorSession {
+  scope: BackdoorScope,
+  device_manager: Arc<ServerDeviceManager>,
+  sender: Sender<ButtplugSerializedMessage>,
+  broadcaster: broadcast::Sender<String>,
+  alive: Arc<AtomicBool>,
+  restart_policy: Arc<Mutex<BackdoorRestartPolicy>>,
+  // Only brought up (and handshaken) the first time a typed request is made.
+  typed_server: OnceCell<ButtplugServerDowngradeWrapper>,
+  message_id: AtomicU32,
+  request_timeout_ms: AtomicU64,
+}
+
+impl BackdoorSession {
+  pub(super) fn new(
+    device_manager: Arc<ServerDeviceManager>,
+    scope: BackdoorScope,
+    restart_policy: BackdoorRestartPolicy,
+    request_timeout: Duration,
+  ) -> Result<Self, BackdoorServerError> {
+    let server = ButtplugRemoteServer::new(backdoor_server(&device_manager)?);
+    let (s_in, r_in) = mpsc::channel(255);
+    let (s_stream, _) = broadcast::channel(255);
+    let alive = Arc::new(AtomicBool::new(true));
+    let restart_policy = Arc::new(Mutex::new(restart_policy));
+    tokio::spawn(run_backdoor_server(
+      server,
+      r_in,
+      s_stream.clone(),
+      alive.clone(),
+      restart_policy.clone(),
+    ));
+    Ok(Self {
+      scope,
+      device_manager,
+      sender: s_in,
+      broadcaster: s_stream,
+      alive,
+      restart_policy,
+      typed_server: OnceCell::new(),
+      message_id: AtomicU32::new(1),
+      request_timeout_ms: AtomicU64::new(request_timeout.as_millis() as u64),
+    })
+  }
+
+  pub fn scope(&self) -> BackdoorScope {
+    self.scope
+  }
+
+  /// Replies to messages sent with [BackdoorSession::parse_message], plus device events.
+  pub fn event_stream(&self) -> impl Stream<Item = String> + '_ {
+    convert_broadcast_receiver_to_stream(self.broadcaster.subscribe())
+  }
+
+  /// True if the JSON side of the session is currently running. While it's being restarted, or if
+  /// the restart policy has given up on it, this is false.
+  pub fn is_alive(&self) -> bool {
+    self.alive.load(Ordering::Relaxed) && !self.sender.is_closed()
+  }
+
+  pub fn restart_policy(&self) -> BackdoorRestartPolicy {
+    *self.restart_policy.lock().unwrap()
+  }
+
+  pub fn set_restart_policy(&self, policy: BackdoorRestartPolicy) {
+    *self.restart_policy.lock().unwrap() = policy;
+  }
+
+  /// Sends a raw JSON message to the session. Replies show up on [BackdoorSession::event_stream].
+  /// Messages in the array that the session's scope doesn't allow are held back and listed in a
+  /// [BackdoorServerError::PermissionDenied], the rest are still sent. Fails with
+  /// [BackdoorServerError::NotRunning] if the session has stopped for good (see
+  /// [BackdoorRestartPolicy]). Messages sent while it's restarting are delivered once it's back up.
+  pub async fn parse_message(&self, msg: &str) -> Result<(), BackdoorServerError> {
+    let (allowed, denied) = self.scope.filter_json_messages(msg);
+    if let Some(allowed) = allowed {
+      self
+        .sender
+        .send(ButtplugSerializedMessage::Text(allowed))
+        .await
+        .map_err(|_| BackdoorServerError::NotRunning)?;
+    }
+    if denied.is_empty() {
+      Ok(())
+    } else {
+      Err(BackdoorServerError::PermissionDenied {
+        denied,
+        scope: self.scope,
+      })
+    }
+  }
+
+  pub fn request_timeout(&self) -> Duration {
+    Duration::from_millis(self.request_timeout_ms.load(Ordering::Relaxed))
+  }
+
+  /// Sets how long typed requests wait for a reply before failing with
+  /// [BackdoorServerError::Timeout].
+  pub fn set_request_timeout(&self, timeout: Duration) {
+    self
+      .request_timeout_ms
+      .store(timeout.as_millis() as u64, Ordering::Relaxed);
+  }
+
+  fn next_message_id(&self) -> u32 {
+    // Id 0 is reserved for server events, so skip it if we ever wrap around.
+    loop {
+      let id = self.message_id.fetch_add(1, Ordering::Relaxed);
+      if id != 0 {
+        return id;
+      }
+    }
+  }
+
+  async fn request(
+    &self,
+    server: &ButtplugServerDowngradeWrapper,
+    mut msg: ButtplugClientMessageV3,
+  ) -> Result<ButtplugServerMessageV3, BackdoorServerError> {
+    msg.set_id(self.next_message_id());
+    match server
+      .parse_message(ButtplugClientMessageVariant::V3(msg))
+      .await
+    {
+      Ok(ButtplugServerMessageVariant::V3(ButtplugServerMessageV3::Error(e)))
+      | Err(ButtplugServerMessageVariant::V3(ButtplugServerMessageV3::Error(e))) => {
+        Err(BackdoorServerError::ButtplugError(e.original_error()))
+      }
+      Ok(ButtplugServerMessageVariant::V3(reply)) => Ok(reply),
+      _ => Err(BackdoorServerError::UnexpectedResponse),
+    }
+  }
+
+  async fn typed_server(&self) -> Result<&ButtplugServerDowngradeWrapper, BackdoorServerError> {
+    self
+      .typed_server
+      .get_or_try_init(|| async {
+        let server = ButtplugServerDowngradeWrapper::new(backdoor_server(&self.device_manager)?);
+        self
+          .request(
+            &server,
+            RequestServerInfoV1::new(
+              "Intiface Backdoor Client",
+              BUTTPLUG_CURRENT_MESSAGE_SPEC_VERSION,
+            )
+            .into(),
+          )
+          .await?;
+        Ok(server)
+      })
+      .await
+  }
+
+  /// Sends a message to the device manager and waits for its reply. The message id is filled in, and
+  /// errors from the server come back as [BackdoorServerError::ButtplugError].
+  pub async fn send_message(
+    &self,
+    msg: ButtplugClientMessageV3,
+  ) -> Result<ButtplugServerMessageV3, BackdoorServerError> {
+    self.scope.check_message(&msg)?;
+    let timeout = self.request_timeout();
+    tokio::time::timeout(timeout, async {
+      let server = self.typed_server().await?;
+      self.request(server, msg).await
+    })
+    .await
+    .map_err(|_| BackdoorServerError::Timeout(timeout))?
+  }
+
+  pub async fn device_list(&self) -> Result<ButtplugServerMessageV3, BackdoorServerError> {
+    self
+      .send_message(RequestDeviceListV0::default().into())
+      .await
+  }
+
+  pub async fn send_scalar(
+    &self,
+    device_index: u32,
+    scalars: Vec<ScalarSubcommandV3>,
+  ) -> Result<ButtplugServerMessageV3, BackdoorServerError> {
+    self
+      .send_message(ScalarCmdV3::new(device_index, scalars).into())
+      .await
+  }
+
+  pub async fn stop_device(
+    &self,
+    device_index: u32,
+  ) -> Result<ButtplugServerMessageV3, BackdoorServerError> {
+    self
+      .send_message(StopDeviceCmdV0::new(device_index).into())
+      .await
+  }
+
+  pub async fn stop_all(&self) -> Result<ButtplugServerMessageV3, BackdoorServerError> {
+    self.send_message(StopAllDevicesV0::default().into()).await
+  }
+
+  pub async fn read_sensor(
+    &self,
+    device_index: u32,
+    sensor_index: u32,
+    sensor_type: SensorType,
+  ) -> Result<ButtplugServerMessageV3, BackdoorServerError> {
+    self
+      .send_message(SensorReadCmdV3::new(device_index, sensor_index, sensor_type).into())
+      .await
+  }
+}
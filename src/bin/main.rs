@@ -1,9 +1,13 @@
 use argh::FromArgs;
+use futures::pin_mut;
 use getset::{CopyGetters, Getters};
+#[cfg(feature = "fault-injection")]
+use intiface_engine::InjectedFault;
 use intiface_engine::{
-  EngineOptions, EngineOptionsBuilder, IntifaceEngine, IntifaceEngineError, IntifaceError,
+  discover_engines, export_user_config_bundle, import_user_config_file, EngineOptions,
+  EngineOptionsBuilder, IntifaceEngine, IntifaceEngineError, IntifaceError, UserConfigImportReport,
 };
-use std::fs;
+use std::{env, fs, str::FromStr, time::Duration};
 use tokio::{select, signal::ctrl_c};
 use tracing::{debug, info, Level};
 use tracing_subscriber::{
@@ -30,13 +34,31 @@ pub struct IntifaceCLIArguments {
   #[getset(get_copy = "pub")]
   server_version: bool,
 
+  /// list intiface engines advertising themselves via mdns on the local network and exit.
+  #[argh(switch)]
+  #[getset(get_copy = "pub")]
+  discover: bool,
+
+  /// path to a TOML (or .json) config file with engine options. Options can also be set via
+  /// INTIFACE_<OPTION_NAME> environment variables (e.g. INTIFACE_WEBSOCKET_PORT), which override the
+  /// file. Options passed on the command line override both, and every switch has a --no-<switch>
+  /// counterpart to turn it off.
+  #[argh(option)]
+  #[getset(get = "pub")]
+  config: Option<String>,
+
   // Options that set up the server networking
-  /// if passed, websocket server listens on all interfaces. Otherwise, only
+  /// if passed, websocket server (or repeater) listens on all interfaces. Otherwise, only
   /// listen on 127.0.0.1.
   #[argh(switch)]
   #[getset(get_copy = "pub")]
   websocket_use_all_interfaces: bool,
 
+  /// turn off --websocket-use-all-interfaces, even if the config file or environment turns it on
+  #[argh(switch)]
+  #[getset(get_copy = "pub")]
+  no_websocket_use_all_interfaces: bool,
+
   /// insecure port for websocket servers.
   #[argh(option)]
   #[getset(get_copy = "pub")]
@@ -53,28 +75,52 @@ pub struct IntifaceCLIArguments {
   #[getset(get_copy = "pub")]
   frontend_websocket_port: Option<u16>,
 
+  /// if passed, allow local (localhost only) websocket access to the backdoor server on this port.
+  /// Clients must send the backdoor token as "Authorization: Bearer <token>".
+  #[argh(option)]
+  #[getset(get_copy = "pub")]
+  backdoor_websocket_port: Option<u16>,
+
+  /// file containing the backdoor token (can also be set via INTIFACE_BACKDOOR_TOKEN)
+  #[argh(option)]
+  #[getset(get = "pub")]
+  backdoor_token_file: Option<String>,
+
   // Options that set up Buttplug server parameters
-  /// name of server to pass to connecting clients.
+  /// name of server to pass to connecting clients (defaults to "Buttplug Server").
   #[argh(option)]
-  #[argh(default = "\"Buttplug Server\".to_owned()")]
   #[getset(get = "pub")]
-  server_name: String,
+  server_name: Option<String>,
 
   /// path to the device configuration file
   #[argh(option)]
   #[getset(get = "pub")]
   device_config_file: Option<String>,
 
-  /// path to user device configuration file
+  /// path to user device configuration file, created if it doesn't exist. Changes are saved back to it.
   #[argh(option)]
   #[getset(get = "pub")]
   user_device_config_file: Option<String>,
 
+  /// number of previous user device config versions to keep when saving (defaults to 3)
+  #[argh(option)]
+  #[getset(get_copy = "pub")]
+  user_device_config_backups: Option<usize>,
+
+  /// if set, reload device configuration when the device config files change
+  #[argh(switch)]
+  #[getset(get_copy = "pub")]
+  watch_device_config: bool,
+
+  /// turn off --watch-device-config, even if the config file or environment turns it on
+  #[argh(switch)]
+  #[getset(get_copy = "pub")]
+  no_watch_device_config: bool,
+
   /// ping timeout maximum for server (in milliseconds)
   #[argh(option)]
-  #[argh(default = "0")]
   #[getset(get_copy = "pub")]
-  max_ping_time: u32,
+  max_ping_time: Option<u32>,
 
   /// set log level for output
   #[allow(dead_code)]
@@ -87,47 +133,92 @@ pub struct IntifaceCLIArguments {
   #[getset(get_copy = "pub")]
   allow_raw: bool,
 
+  /// turn off --allow-raw, even if the config file or environment turns it on
+  #[argh(switch)]
+  #[getset(get_copy = "pub")]
+  no_allow_raw: bool,
+
   /// turn off bluetooth le device support
   #[argh(switch)]
   #[getset(get_copy = "pub")]
   use_bluetooth_le: bool,
 
+  /// turn off --use-bluetooth-le, even if the config file or environment turns it on
+  #[argh(switch)]
+  #[getset(get_copy = "pub")]
+  no_use_bluetooth_le: bool,
+
   /// turn off serial device support
   #[argh(switch)]
   #[getset(get_copy = "pub")]
   use_serial: bool,
 
+  /// turn off --use-serial, even if the config file or environment turns it on
+  #[argh(switch)]
+  #[getset(get_copy = "pub")]
+  no_use_serial: bool,
+
   /// turn off hid device support
   #[allow(dead_code)]
   #[argh(switch)]
   #[getset(get_copy = "pub")]
   use_hid: bool,
 
+  /// turn off --use-hid, even if the config file or environment turns it on
+  #[argh(switch)]
+  #[getset(get_copy = "pub")]
+  no_use_hid: bool,
+
   /// turn off lovense dongle serial device support
   #[argh(switch)]
   #[getset(get_copy = "pub")]
   use_lovense_dongle_serial: bool,
 
+  /// turn off --use-lovense-dongle-serial, even if the config file or environment turns it on
+  #[argh(switch)]
+  #[getset(get_copy = "pub")]
+  no_use_lovense_dongle_serial: bool,
+
   /// turn off lovense dongle hid device support
   #[argh(switch)]
   #[getset(get_copy = "pub")]
   use_lovense_dongle_hid: bool,
 
+  /// turn off --use-lovense-dongle-hid, even if the config file or environment turns it on
+  #[argh(switch)]
+  #[getset(get_copy = "pub")]
+  no_use_lovense_dongle_hid: bool,
+
   /// turn off xinput gamepad device support (windows only)
   #[argh(switch)]
   #[getset(get_copy = "pub")]
   use_xinput: bool,
 
+  /// turn off --use-xinput, even if the config file or environment turns it on
+  #[argh(switch)]
+  #[getset(get_copy = "pub")]
+  no_use_xinput: bool,
+
   /// turn on lovense connect app device support (off by default)
   #[argh(switch)]
   #[getset(get_copy = "pub")]
   use_lovense_connect: bool,
 
+  /// turn off --use-lovense-connect, even if the config file or environment turns it on
+  #[argh(switch)]
+  #[getset(get_copy = "pub")]
+  no_use_lovense_connect: bool,
+
   /// turn on websocket server device comm manager
   #[argh(switch)]
   #[getset(get_copy = "pub")]
   use_device_websocket_server: bool,
 
+  /// turn off --use-device-websocket-server, even if the config file or environment turns it on
+  #[argh(switch)]
+  #[getset(get_copy = "pub")]
+  no_use_device_websocket_server: bool,
+
   /// port for device websocket server comm manager (defaults to 54817)
   #[argh(option)]
   #[getset(get_copy = "pub")]
@@ -138,16 +229,51 @@ pub struct IntifaceCLIArguments {
   #[getset(get_copy = "pub")]
   broadcast_server_mdns: bool,
 
+  /// turn off --broadcast-server-mdns, even if the config file or environment turns it on
+  #[argh(switch)]
+  #[getset(get_copy = "pub")]
+  no_broadcast_server_mdns: bool,
+
   /// mdns suffix, will be appended to instance names for advertised mdns services (optional, ignored if broadcast_mdns is not set)
   #[argh(option)]
   #[getset(get = "pub")]
   mdns_suffix: Option<String>,
 
+  /// network interface name or address to advertise mdns on (can be passed multiple times, defaults to all)
+  #[argh(option)]
+  #[getset(get = "pub")]
+  mdns_interface: Vec<String>,
+
+  /// only advertise ipv4 addresses via mdns
+  #[argh(switch)]
+  #[getset(get_copy = "pub")]
+  mdns_ipv4_only: bool,
+
+  /// turn off --mdns-ipv4-only, even if the config file or environment turns it on
+  #[argh(switch)]
+  #[getset(get_copy = "pub")]
+  no_mdns_ipv4_only: bool,
+
+  /// only advertise ipv6 addresses via mdns
+  #[argh(switch)]
+  #[getset(get_copy = "pub")]
+  mdns_ipv6_only: bool,
+
+  /// turn off --mdns-ipv6-only, even if the config file or environment turns it on
+  #[argh(switch)]
+  #[getset(get_copy = "pub")]
+  no_mdns_ipv6_only: bool,
+
   /// if set, use repeater mode instead of engine mode
   #[argh(switch)]
   #[getset(get_copy = "pub")]
   repeater: bool,
 
+  /// turn off --repeater, even if the config file or environment turns it on
+  #[argh(switch)]
+  #[getset(get_copy = "pub")]
+  no_repeater: bool,
+
   /// if set, use repeater mode instead of engine mode
   #[argh(option)]
   #[getset(get_copy = "pub")]
@@ -158,18 +284,170 @@ pub struct IntifaceCLIArguments {
   #[getset(get = "pub")]
   repeater_remote_address: Option<String>,
 
-  #[cfg(debug_assertions)]
+  /// mdns instance name of the engine to use as the repeater upstream (instead of repeater_remote_address)
+  #[argh(option)]
+  #[getset(get = "pub")]
+  repeater_remote_mdns_name: Option<String>,
+
+  /// additional upstream server address for repeater fan-out mode (can be passed multiple times)
+  #[argh(option)]
+  #[getset(get = "pub")]
+  repeater_fan_out_address: Vec<String>,
+
+  /// path to a PEM certificate chain, enables TLS (wss://) for repeater clients
+  #[argh(option)]
+  #[getset(get = "pub")]
+  repeater_tls_cert: Option<String>,
+
+  /// path to the PEM private key for the repeater TLS certificate
+  #[argh(option)]
+  #[getset(get = "pub")]
+  repeater_tls_key: Option<String>,
+
+  /// if set, record repeater sessions as JSONL files in this directory
+  #[argh(option)]
+  #[getset(get = "pub")]
+  repeater_record_dir: Option<String>,
+
+  /// if set, replay the client side of a recorded repeater session against the repeater remote address, then exit
+  #[argh(option)]
+  #[getset(get = "pub")]
+  repeater_replay: Option<String>,
+
+  #[cfg(feature = "fault-injection")]
   /// crash the main thread (that holds the runtime)
   #[argh(switch)]
   #[getset(get_copy = "pub")]
   crash_main_thread: bool,
 
-  #[allow(dead_code)]
-  #[cfg(debug_assertions)]
+  #[cfg(feature = "fault-injection")]
   /// crash the task thread (for testing logging/reporting)
   #[argh(switch)]
   #[getset(get_copy = "pub")]
   crash_task_thread: bool,
+
+  #[cfg(feature = "fault-injection")]
+  /// inject a fault after the engine starts, as kind[@seconds]. Kinds are panic-main-thread,
+  /// panic-task, drop-client-connection and stall-device-manager (can be passed multiple times)
+  #[argh(option)]
+  #[getset(get = "pub")]
+  inject_fault: Vec<InjectedFault>,
+
+  #[argh(subcommand)]
+  #[getset(get = "pub")]
+  command: Option<IntifaceCLICommand>,
+}
+
+#[derive(FromArgs)]
+#[argh(subcommand)]
+pub enum IntifaceCLICommand {
+  ExportUserConfig(ExportUserConfigCommand),
+  ImportUserConfig(ImportUserConfigCommand),
+}
+
+/// export the user device config as a versioned bundle, then exit.
+#[derive(FromArgs, Getters)]
+#[argh(subcommand, name = "export-user-config")]
+pub struct ExportUserConfigCommand {
+  /// user device config to export (defaults to --user-device-config-file)
+  #[argh(option)]
+  #[getset(get = "pub")]
+  input: Option<String>,
+
+  /// file to write the bundle to (defaults to stdout)
+  #[argh(option)]
+  #[getset(get = "pub")]
+  output: Option<String>,
+}
+
+/// import a user device config bundle or older format user device config, converting it to the
+/// current format, then exit.
+#[derive(FromArgs, Getters)]
+#[argh(subcommand, name = "import-user-config")]
+pub struct ImportUserConfigCommand {
+  /// bundle or user device config to import
+  #[argh(positional)]
+  #[getset(get = "pub")]
+  input: String,
+
+  /// user device config to write (defaults to --user-device-config-file). An existing file is kept
+  /// as a backup.
+  #[argh(option)]
+  #[getset(get = "pub")]
+  output: Option<String>,
+}
+
+fn print_import_report(report: &UserConfigImportReport) {
+  eprintln!(
+    "User config version {}{}: {} devices and {} protocols{}",
+    report.source_version(),
+    if report.from_bundle() {
+      " (bundle)"
+    } else {
+      ""
+    },
+    report.devices_imported(),
+    report.protocols_imported(),
+    if report.migrated() {
+      ", migrated to the current format"
+    } else {
+      ""
+    }
+  );
+  for warning in report.warnings() {
+    eprintln!("  Warning: {}", warning);
+  }
+}
+
+async fn run_command(
+  args: &IntifaceCLIArguments,
+  command: &IntifaceCLICommand,
+) -> Result<(), IntifaceError> {
+  // Migrating older user configs fills in device definitions from the device config.
+  let device_config_json = args
+    .device_config_file()
+    .as_ref()
+    .map(|path| {
+      fs::read_to_string(path).map_err(|e| {
+        IntifaceError::new(&format!(
+          "Error opening external device configuration: {:?}",
+          e
+        ))
+      })
+    })
+    .transpose()?;
+  let user_config_path = |path: &Option<String>| {
+    path
+      .as_ref()
+      .or(args.user_device_config_file().as_ref())
+      .cloned()
+      .ok_or_else(|| {
+        IntifaceError::new("No user device config given, use --user-device-config-file")
+      })
+  };
+  match command {
+    IntifaceCLICommand::ExportUserConfig(export) => {
+      let input = user_config_path(export.input())?;
+      let contents = fs::read_to_string(&input).map_err(|e| {
+        IntifaceError::new(&format!("Error opening user config {}: {:?}", input, e))
+      })?;
+      let (bundle, report) = export_user_config_bundle(&contents, &device_config_json)?;
+      match export.output() {
+        Some(output) => fs::write(output, bundle).map_err(|e| {
+          IntifaceError::new(&format!("Cannot write bundle to {}: {:?}", output, e))
+        })?,
+        None => println!("{}", bundle),
+      }
+      print_import_report(&report);
+    }
+    IntifaceCLICommand::ImportUserConfig(import) => {
+      let output = user_config_path(import.output())?;
+      let report = import_user_config_file(import.input(), &output, &device_config_json).await?;
+      print_import_report(&report);
+      eprintln!("Wrote user config to {}", output);
+    }
+  }
+  Ok(())
 }
 
 pub fn setup_console_logging(log_level: Option<Level>) {
@@ -193,10 +471,217 @@ pub fn setup_console_logging(log_level: Option<Level>) {
   println!("Intiface Server, starting up with stdout output.");
 }
 
+const ENV_PREFIX: &str = "INTIFACE_";
+
+fn env_string(name: &str) -> Option<String> {
+  env::var(format!("{}{}", ENV_PREFIX, name))
+    .ok()
+    .filter(|value| !value.is_empty())
+}
+
+fn env_parse<T: FromStr>(name: &str) -> Result<Option<T>, IntifaceError> {
+  env_string(name)
+    .map(|value| {
+      value.trim().parse::<T>().map_err(|_| {
+        IntifaceError::new(&format!(
+          "Invalid value for {}{}: {}",
+          ENV_PREFIX, name, value
+        ))
+      })
+    })
+    .transpose()
+}
+
+fn env_bool(name: &str) -> Result<Option<bool>, IntifaceError> {
+  env_string(name)
+    .map(|value| match value.trim().to_ascii_lowercase().as_str() {
+      "1" | "true" | "yes" | "on" => Ok(true),
+      "0" | "false" | "no" | "off" => Ok(false),
+      _ => Err(IntifaceError::new(&format!(
+        "Invalid value for {}{}: {} (expected true or false)",
+        ENV_PREFIX, name, value
+      ))),
+    })
+    .transpose()
+}
+
+// Lists are comma separated, and are added to whatever the config file already had.
+fn env_list(name: &str) -> Vec<String> {
+  env_string(name)
+    .map(|value| {
+      value
+        .split(',')
+        .map(|item| item.trim().to_owned())
+        .filter(|item| !item.is_empty())
+        .collect()
+    })
+    .unwrap_or_default()
+}
+
+/// Applies INTIFACE_* environment variables on top of the options in the builder. Each variable is
+/// the option field name in upper case.
+fn apply_env_options(builder: &mut EngineOptionsBuilder) -> Result<(), IntifaceError> {
+  if let Some(value) = env_string("DEVICE_CONFIG_JSON") {
+    builder.device_config_json(&value);
+  }
+  if let Some(value) = env_string("DEVICE_CONFIG_PATH") {
+    builder.device_config_path(&value);
+  }
+  if let Some(value) = env_string("USER_DEVICE_CONFIG_JSON") {
+    builder.user_device_config_json(&value);
+  }
+  if let Some(value) = env_string("USER_DEVICE_CONFIG_PATH") {
+    builder.user_device_config_path(&value);
+  }
+  if let Some(value) = env_parse("USER_DEVICE_CONFIG_BACKUP_COUNT")? {
+    builder.user_device_config_backup_count(value);
+  }
+  if let Some(value) = env_bool("WATCH_DEVICE_CONFIG")? {
+    builder.watch_device_config(value);
+  }
+  if let Some(value) = env_string("SERVER_NAME") {
+    builder.server_name(&value);
+  }
+  if let Some(value) = env_bool("WEBSOCKET_USE_ALL_INTERFACES")? {
+    builder.websocket_use_all_interfaces(value);
+  }
+  if let Some(value) = env_parse("WEBSOCKET_PORT")? {
+    builder.websocket_port(value);
+  }
+  if let Some(value) = env_string("WEBSOCKET_CLIENT_ADDRESS") {
+    builder.websocket_client_address(&value);
+  }
+  if let Some(value) = env_parse("FRONTEND_WEBSOCKET_PORT")? {
+    builder.frontend_websocket_port(value);
+  }
+  if let Some(value) = env_bool("FRONTEND_IN_PROCESS_CHANNEL")? {
+    builder.frontend_in_process_channel(value);
+  }
+  if let Some(value) = env_parse("BACKDOOR_WEBSOCKET_PORT")? {
+    builder.backdoor_websocket_port(value);
+  }
+  if let Some(value) = env_string("BACKDOOR_TOKEN") {
+    builder.backdoor_token(&value);
+  }
+  if let Some(value) = env_parse("MAX_PING_TIME")? {
+    builder.max_ping_time(value);
+  }
+  if let Some(value) = env_bool("ALLOW_RAW_MESSAGES")? {
+    builder.allow_raw_messages(value);
+  }
+  if let Some(value) = env_bool("USE_BLUETOOTH_LE")? {
+    builder.use_bluetooth_le(value);
+  }
+  if let Some(value) = env_bool("USE_SERIAL_PORT")? {
+    builder.use_serial_port(value);
+  }
+  if let Some(value) = env_bool("USE_HID")? {
+    builder.use_hid(value);
+  }
+  if let Some(value) = env_bool("USE_LOVENSE_DONGLE_SERIAL")? {
+    builder.use_lovense_dongle_serial(value);
+  }
+  if let Some(value) = env_bool("USE_LOVENSE_DONGLE_HID")? {
+    builder.use_lovense_dongle_hid(value);
+  }
+  if let Some(value) = env_bool("USE_XINPUT")? {
+    builder.use_xinput(value);
+  }
+  if let Some(value) = env_bool("USE_LOVENSE_CONNECT")? {
+    builder.use_lovense_connect(value);
+  }
+  if let Some(value) = env_bool("USE_DEVICE_WEBSOCKET_SERVER")? {
+    builder.use_device_websocket_server(value);
+  }
+  if let Some(value) = env_parse("DEVICE_WEBSOCKET_SERVER_PORT")? {
+    builder.device_websocket_server_port(value);
+  }
+  #[cfg(feature = "fault-injection")]
+  {
+    if let Some(value) = env_bool("CRASH_MAIN_THREAD")? {
+      builder.crash_main_thread(value);
+    }
+    if let Some(value) = env_bool("CRASH_TASK_THREAD")? {
+      builder.crash_task_thread(value);
+    }
+    for value in env_list("INJECTED_FAULTS") {
+      builder.inject_fault(value.parse()?);
+    }
+  }
+  if let Some(value) = env_bool("BROADCAST_SERVER_MDNS")? {
+    builder.broadcast_server_mdns(value);
+  }
+  if let Some(value) = env_string("MDNS_SUFFIX") {
+    builder.mdns_suffix(&value);
+  }
+  for value in env_list("MDNS_INTERFACES") {
+    builder.mdns_interface(&value);
+  }
+  if let Some(value) = env_bool("MDNS_IPV4_ONLY")? {
+    builder.mdns_ipv4_only(value);
+  }
+  if let Some(value) = env_bool("MDNS_IPV6_ONLY")? {
+    builder.mdns_ipv6_only(value);
+  }
+  if let Some(value) = env_bool("REPEATER_MODE")? {
+    builder.repeater_mode(value);
+  }
+  if let Some(value) = env_parse("REPEATER_LOCAL_PORT")? {
+    builder.repeater_local_port(value);
+  }
+  if let Some(value) = env_string("REPEATER_REMOTE_ADDRESS") {
+    builder.repeater_remote_address(&value);
+  }
+  if let Some(value) = env_string("REPEATER_REMOTE_MDNS_NAME") {
+    builder.repeater_remote_mdns_name(&value);
+  }
+  for value in env_list("REPEATER_FAN_OUT_ADDRESSES") {
+    builder.repeater_fan_out_address(&value);
+  }
+  if let Some(value) = env_string("REPEATER_TLS_CERT_PATH") {
+    builder.repeater_tls_cert_path(&value);
+  }
+  if let Some(value) = env_string("REPEATER_TLS_KEY_PATH") {
+    builder.repeater_tls_key_path(&value);
+  }
+  if let Some(value) = env_string("REPEATER_RECORD_DIRECTORY") {
+    builder.repeater_record_directory(&value);
+  }
+  if let Some(value) = env_string("REPEATER_REPLAY_PATH") {
+    builder.repeater_replay_path(&value);
+  }
+  Ok(())
+}
+
+// Command line switches come in --name/--no-name pairs. None means neither was passed, so whatever
+// the config file or environment set stays.
+fn switch_value(on: bool, off: bool, name: &str) -> Result<Option<bool>, IntifaceError> {
+  match (on, off) {
+    (true, true) => Err(IntifaceError::new(&format!(
+      "--{} and --no-{} cannot be passed together",
+      name, name
+    ))),
+    (true, false) => Ok(Some(true)),
+    (false, true) => Ok(Some(false)),
+    (false, false) => Ok(None),
+  }
+}
+
 impl TryFrom<IntifaceCLIArguments> for EngineOptions {
   type Error = IntifaceError;
   fn try_from(args: IntifaceCLIArguments) -> Result<Self, IntifaceError> {
-    let mut builder = EngineOptionsBuilder::default();
+    // Anything in the config file is our baseline, then environment variables override that, and
+    // the command line overrides both.
+    let file_options = if let Some(config) = args.config() {
+      info!("Intiface CLI Options: Config File {}", config);
+      EngineOptions::from_config_file(config)?
+    } else {
+      EngineOptions::default()
+    };
+    let mut env_builder = EngineOptionsBuilder::from(file_options);
+    apply_env_options(&mut env_builder)?;
+    let base = env_builder.finish();
+    let mut builder = EngineOptionsBuilder::from(base.clone());
 
     if let Some(deviceconfig) = args.device_config_file() {
       info!(
@@ -204,7 +689,9 @@ impl TryFrom<IntifaceCLIArguments> for EngineOptions {
         deviceconfig
       );
       match fs::read_to_string(deviceconfig) {
-        Ok(cfg) => builder.device_config_json(&cfg),
+        Ok(cfg) => builder
+          .device_config_json(&cfg)
+          .device_config_path(deviceconfig),
         Err(err) => {
           return Err(IntifaceError::new(&format!(
             "Error opening external device configuration: {:?}",
@@ -219,39 +706,104 @@ impl TryFrom<IntifaceCLIArguments> for EngineOptions {
         "Intiface CLI Options: User Device Config {}",
         userdeviceconfig
       );
-      match fs::read_to_string(userdeviceconfig) {
-        Ok(cfg) => builder.user_device_config_json(&cfg),
-        Err(err) => {
-          return Err(IntifaceError::new(&format!(
-            "Error opening user device configuration: {:?}",
-            err
-          )))
-        }
-      };
+      // The engine loads this (or creates it if it doesn't exist yet), and saves changes back to it.
+      builder.user_device_config_path(userdeviceconfig);
     }
 
+    let server_name = match args.server_name() {
+      Some(name) => name.as_str(),
+      None if !base.server_name().is_empty() => base.server_name().as_str(),
+      None => "Buttplug Server",
+    };
+
+    if let Some(value) = switch_value(args.allow_raw(), args.no_allow_raw(), "allow-raw")? {
+      builder.allow_raw_messages(value);
+    }
+    if let Some(value) = switch_value(
+      args.watch_device_config(),
+      args.no_watch_device_config(),
+      "watch-device-config",
+    )? {
+      builder.watch_device_config(value);
+    }
+    if let Some(value) = switch_value(
+      args.websocket_use_all_interfaces(),
+      args.no_websocket_use_all_interfaces(),
+      "websocket-use-all-interfaces",
+    )? {
+      builder.websocket_use_all_interfaces(value);
+    }
+    if let Some(value) = switch_value(
+      args.use_bluetooth_le(),
+      args.no_use_bluetooth_le(),
+      "use-bluetooth-le",
+    )? {
+      builder.use_bluetooth_le(value);
+    }
+    if let Some(value) = switch_value(args.use_serial(), args.no_use_serial(), "use-serial")? {
+      builder.use_serial_port(value);
+    }
+    if let Some(value) = switch_value(args.use_hid(), args.no_use_hid(), "use-hid")? {
+      builder.use_hid(value);
+    }
+    if let Some(value) = switch_value(
+      args.use_lovense_dongle_serial(),
+      args.no_use_lovense_dongle_serial(),
+      "use-lovense-dongle-serial",
+    )? {
+      builder.use_lovense_dongle_serial(value);
+    }
+    if let Some(value) = switch_value(
+      args.use_lovense_dongle_hid(),
+      args.no_use_lovense_dongle_hid(),
+      "use-lovense-dongle-hid",
+    )? {
+      builder.use_lovense_dongle_hid(value);
+    }
+    if let Some(value) = switch_value(args.use_xinput(), args.no_use_xinput(), "use-xinput")? {
+      builder.use_xinput(value);
+    }
+    if let Some(value) = switch_value(
+      args.use_lovense_connect(),
+      args.no_use_lovense_connect(),
+      "use-lovense-connect",
+    )? {
+      builder.use_lovense_connect(value);
+    }
+    if let Some(value) = switch_value(
+      args.use_device_websocket_server(),
+      args.no_use_device_websocket_server(),
+      "use-device-websocket-server",
+    )? {
+      builder.use_device_websocket_server(value);
+    }
     builder
-      .allow_raw_messages(args.allow_raw())
-      .websocket_use_all_interfaces(args.websocket_use_all_interfaces())
-      .use_bluetooth_le(args.use_bluetooth_le())
-      .use_serial_port(args.use_serial())
-      .use_hid(args.use_hid())
-      .use_lovense_dongle_serial(args.use_lovense_dongle_serial())
-      .use_lovense_dongle_hid(args.use_lovense_dongle_hid())
-      .use_xinput(args.use_xinput())
-      .use_lovense_connect(args.use_lovense_connect())
-      .use_device_websocket_server(args.use_device_websocket_server())
-      .max_ping_time(args.max_ping_time())
-      .server_name(args.server_name())
-      .broadcast_server_mdns(args.broadcast_server_mdns());
-
-    #[cfg(debug_assertions)]
+      .max_ping_time(args.max_ping_time().unwrap_or(base.max_ping_time()))
+      .server_name(server_name);
+    let broadcast_server_mdns = switch_value(
+      args.broadcast_server_mdns(),
+      args.no_broadcast_server_mdns(),
+      "broadcast-server-mdns",
+    )?
+    .unwrap_or(base.broadcast_server_mdns());
+    builder.broadcast_server_mdns(broadcast_server_mdns);
+
+    #[cfg(feature = "fault-injection")]
     {
-      builder
-        .crash_main_thread(args.crash_main_thread())
-        .crash_task_thread(args.crash_task_thread());
+      if args.crash_main_thread() {
+        builder.crash_main_thread(true);
+      }
+      if args.crash_task_thread() {
+        builder.crash_task_thread(true);
+      }
+      for fault in args.inject_fault() {
+        builder.inject_fault(*fault);
+      }
     }
 
+    if let Some(value) = args.user_device_config_backups() {
+      builder.user_device_config_backup_count(value);
+    }
     if let Some(value) = args.websocket_port() {
       builder.websocket_port(value);
     }
@@ -261,21 +813,80 @@ impl TryFrom<IntifaceCLIArguments> for EngineOptions {
     if let Some(value) = args.frontend_websocket_port() {
       builder.frontend_websocket_port(value);
     }
+    if let Some(value) = args.backdoor_websocket_port() {
+      builder.backdoor_websocket_port(value);
+    }
+    if let Some(path) = args.backdoor_token_file() {
+      let token = fs::read_to_string(path)
+        .map_err(|e| IntifaceError::new(&format!("Error opening backdoor token file: {:?}", e)))?;
+      builder.backdoor_token(token.trim());
+    }
     if let Some(value) = args.device_websocket_server_port() {
       builder.device_websocket_server_port(value);
     }
-    if args.broadcast_server_mdns() {
+    if broadcast_server_mdns {
       if let Some(value) = args.mdns_suffix() {
         builder.mdns_suffix(value);
       }
+      for value in args.mdns_interface() {
+        builder.mdns_interface(value);
+      }
+      if let Some(value) = switch_value(
+        args.mdns_ipv4_only(),
+        args.no_mdns_ipv4_only(),
+        "mdns-ipv4-only",
+      )? {
+        builder.mdns_ipv4_only(value);
+      }
+      if let Some(value) = switch_value(
+        args.mdns_ipv6_only(),
+        args.no_mdns_ipv6_only(),
+        "mdns-ipv6-only",
+      )? {
+        builder.mdns_ipv6_only(value);
+      }
+    }
+    if let Some(value) = switch_value(args.repeater(), args.no_repeater(), "repeater")? {
+      builder.repeater_mode(value);
+    }
+    if let Some(value) = args.repeater_port() {
+      builder.repeater_local_port(value);
+    }
+    if let Some(value) = args.repeater_remote_address() {
+      builder.repeater_remote_address(value);
+    }
+    if let Some(value) = args.repeater_remote_mdns_name() {
+      builder.repeater_remote_mdns_name(value);
+    }
+    for value in args.repeater_fan_out_address() {
+      builder.repeater_fan_out_address(value);
+    }
+    if let Some(value) = args.repeater_tls_cert() {
+      builder.repeater_tls_cert_path(value);
+    }
+    if let Some(value) = args.repeater_tls_key() {
+      builder.repeater_tls_key_path(value);
+    }
+    if let Some(value) = args.repeater_record_dir() {
+      builder.repeater_record_directory(value);
+    }
+    if let Some(value) = args.repeater_replay() {
+      builder.repeater_replay_path(value);
     }
     Ok(builder.finish())
   }
 }
 
 #[tokio::main(flavor = "current_thread")] //#[tokio::main]
-async fn main() -> Result<(), IntifaceEngineError> {
+async fn main() {
   let args: IntifaceCLIArguments = argh::from_env();
+  if let Err(e) = run(args).await {
+    eprintln!("Error [{}]: {}", e.code(), e);
+    std::process::exit(1);
+  }
+}
+
+async fn run(args: IntifaceCLIArguments) -> Result<(), IntifaceEngineError> {
   if args.server_version() {
     println!("{}", VERSION);
     return Ok(());
@@ -292,24 +903,253 @@ async fn main() -> Result<(), IntifaceEngineError> {
     return Ok(());
   }
 
+  if args.discover() {
+    let engines = discover_engines(Duration::from_secs(3))
+      .await
+      .map_err(IntifaceEngineError::MdnsError)?;
+    if engines.is_empty() {
+      println!("No engines found.");
+    }
+    for engine in engines {
+      println!(
+        "{} - {} ({}) {:?}",
+        engine.instance_name(),
+        engine
+          .websocket_address()
+          .unwrap_or_else(|| "no address".to_owned()),
+        engine.hostname(),
+        engine.txt()
+      );
+    }
+    return Ok(());
+  }
+
+  if let Some(command) = args.command() {
+    return run_command(&args, command)
+      .await
+      .map_err(IntifaceEngineError::from);
+  }
+
   if args.frontend_websocket_port().is_none() {
     setup_console_logging(args.log());
   }
 
-  let options = EngineOptions::try_from(args).map_err(IntifaceEngineError::from)?;
+  let options = EngineOptions::try_from(args).map_err(IntifaceEngineError::ConfigLoadError)?;
+  options.validate()?;
   let engine = IntifaceEngine::default();
-  select! {
-    result = engine.run(&options, None, &None) => {
-      if let Err(e) = result {
-        println!("Server errored while running:");
-        println!("{:?}", e);
-      }
-    }
+  let run_fut = engine.run(&options, None, &None);
+  pin_mut!(run_fut);
+  let result = select! {
+    result = &mut run_fut => result,
     _ = ctrl_c() => {
       info!("Control-c hit, exiting.");
       engine.stop();
+      // Let the engine finish shutting down, so things like user config get saved.
+      run_fut.await
     }
+  };
+  if let Err(e) = result {
+    println!("Server errored while running:");
+    println!("[{}] {}", e.code(), e);
   }
 
   Ok(())
 }
+
+#[cfg(test)]
+mod test {
+  use super::*;
+  use std::{
+    path::PathBuf,
+    sync::{Mutex, MutexGuard},
+  };
+
+  // Environment variables are shared by every test in the process, so anything that reads them
+  // holds this lock.
+  static ENV_LOCK: Mutex<()> = Mutex::new(());
+
+  struct EnvVars {
+    names: Vec<String>,
+    _lock: MutexGuard<'static, ()>,
+  }
+
+  impl Drop for EnvVars {
+    fn drop(&mut self) {
+      for name in &self.names {
+        env::remove_var(name);
+      }
+    }
+  }
+
+  fn env_vars(vars: &[(&str, &str)]) -> EnvVars {
+    let lock = ENV_LOCK.lock().unwrap_or_else(|e| e.into_inner());
+    let names = vars
+      .iter()
+      .map(|(name, value)| {
+        let name = format!("{}{}", ENV_PREFIX, name);
+        env::set_var(&name, value);
+        name
+      })
+      .collect();
+    EnvVars { names, _lock: lock }
+  }
+
+  fn config_file(name: &str, contents: &str) -> PathBuf {
+    let path = env::temp_dir().join(format!(
+      "intiface-engine-{}-{}.toml",
+      name,
+      std::process::id()
+    ));
+    fs::write(&path, contents).unwrap();
+    path
+  }
+
+  fn parse(args: &[&str]) -> Result<EngineOptions, IntifaceError> {
+    let args = IntifaceCLIArguments::from_args(&["intiface-engine"], args).unwrap();
+    EngineOptions::try_from(args)
+  }
+
+  #[test]
+  fn test_cli_switches() {
+    let _env = env_vars(&[]);
+    let options = parse(&["--allow-raw", "--use-hid", "--repeater"]).unwrap();
+    assert!(options.allow_raw_messages());
+    assert!(options.use_hid());
+    assert!(options.repeater_mode());
+    assert!(!options.use_serial_port());
+
+    let options = parse(&["--no-allow-raw", "--no-repeater"]).unwrap();
+    assert!(!options.allow_raw_messages());
+    assert!(!options.repeater_mode());
+  }
+
+  #[test]
+  fn test_conflicting_switches() {
+    let _env = env_vars(&[]);
+    assert!(parse(&["--allow-raw", "--no-allow-raw"]).is_err());
+    assert!(parse(&["--repeater", "--no-repeater"]).is_err());
+    // mDNS switches are only looked at if mDNS is on.
+    assert!(parse(&["--mdns-ipv4-only", "--no-mdns-ipv4-only"]).is_ok());
+    assert!(parse(&[
+      "--broadcast-server-mdns",
+      "--mdns-ipv4-only",
+      "--no-mdns-ipv4-only"
+    ])
+    .is_err());
+  }
+
+  #[test]
+  fn test_config_file_options() {
+    let _env = env_vars(&[]);
+    let path = config_file(
+      "cli-file",
+      "websocket_port = 12345\nallow_raw_messages = true\nuse_hid = true\nserver_name = \"File\"\n",
+    );
+    let options = parse(&["--config", path.to_str().unwrap()]).unwrap();
+    assert_eq!(options.websocket_port(), Some(12345));
+    assert!(options.allow_raw_messages());
+    assert!(options.use_hid());
+    assert_eq!(options.server_name(), "File");
+    fs::remove_file(path).unwrap();
+  }
+
+  #[test]
+  fn test_layer_precedence() {
+    let path = config_file(
+      "cli-layers",
+      "websocket_port = 12345\nallow_raw_messages = true\nuse_hid = true\n\
+       broadcast_server_mdns = true\nmdns_ipv4_only = true\nrepeater_mode = true\n",
+    );
+    let config = path.to_str().unwrap();
+
+    // Environment overrides the file, including turning switches off.
+    let env = env_vars(&[
+      ("WEBSOCKET_PORT", "12346"),
+      ("ALLOW_RAW_MESSAGES", "false"),
+      ("REPEATER_MODE", "false"),
+    ]);
+    let options = parse(&["--config", config]).unwrap();
+    assert_eq!(options.websocket_port(), Some(12346));
+    assert!(!options.allow_raw_messages());
+    assert!(!options.repeater_mode());
+    assert!(options.use_hid());
+    assert!(options.mdns_ipv4_only());
+
+    // The command line overrides both.
+    let options = parse(&[
+      "--config",
+      config,
+      "--websocket-port",
+      "12347",
+      "--allow-raw",
+      "--no-use-hid",
+      "--no-mdns-ipv4-only",
+      "--repeater",
+    ])
+    .unwrap();
+    assert_eq!(options.websocket_port(), Some(12347));
+    assert!(options.allow_raw_messages());
+    assert!(!options.use_hid());
+    assert!(!options.mdns_ipv4_only());
+    assert!(options.repeater_mode());
+
+    // Turning mDNS off on the command line leaves the file's mDNS options alone.
+    let options = parse(&["--config", config, "--no-broadcast-server-mdns"]).unwrap();
+    assert!(!options.broadcast_server_mdns());
+    assert!(options.mdns_ipv4_only());
+    drop(env);
+    fs::remove_file(path).unwrap();
+  }
+
+  #[test]
+  fn test_apply_env_options() {
+    let _env = env_vars(&[
+      ("SERVER_NAME", "Env"),
+      ("WEBSOCKET_PORT", " 12345 "),
+      ("USE_BLUETOOTH_LE", "yes"),
+      ("USE_SERIAL_PORT", "Off"),
+      ("MDNS_INTERFACES", "eth0, ,wlan0"),
+      ("REPEATER_FAN_OUT_ADDRESSES", "ws://192.0.2.2:12345"),
+      ("REPEATER_REMOTE_ADDRESS", ""),
+    ]);
+    let mut builder = EngineOptionsBuilder::default();
+    builder
+      .use_serial_port(true)
+      .mdns_interface("lo")
+      .repeater_remote_address("ws://192.0.2.1:12345");
+    apply_env_options(&mut builder).unwrap();
+    let options = builder.finish();
+    assert_eq!(options.server_name(), "Env");
+    assert_eq!(options.websocket_port(), Some(12345));
+    assert!(options.use_bluetooth_le());
+    assert!(!options.use_serial_port());
+    // Lists are added to, and empty values are ignored.
+    assert_eq!(options.mdns_interfaces(), &["lo", "eth0", "wlan0"]);
+    assert_eq!(
+      options.repeater_fan_out_addresses(),
+      &["ws://192.0.2.2:12345"]
+    );
+    assert_eq!(
+      options.repeater_remote_address().as_deref(),
+      Some("ws://192.0.2.1:12345")
+    );
+  }
+
+  #[test]
+  fn test_apply_env_options_invalid_values() {
+    for (name, value) in [
+      ("WEBSOCKET_PORT", "not a port"),
+      ("WEBSOCKET_PORT", "70000"),
+      ("ALLOW_RAW_MESSAGES", "maybe"),
+      ("REPEATER_MODE", "2"),
+    ] {
+      let _env = env_vars(&[(name, value)]);
+      assert!(
+        apply_env_options(&mut EngineOptionsBuilder::default()).is_err(),
+        "{}={}",
+        name,
+        value
+      );
+    }
+  }
+}
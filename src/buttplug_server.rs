@@ -1,8 +1,8 @@
 use std::sync::Arc;
 
 use crate::{
-  BackdoorServer, ButtplugRemoteServer, ButtplugServerConnectorError, EngineOptions,
-  IntifaceEngineError, IntifaceError,
+  user_config::load_user_config_json, BackdoorServer, ButtplugRemoteServer,
+  ButtplugServerConnectorError, EngineOptions, IntifaceEngineError, IntifaceError,
 };
 use buttplug::{
   core::{
@@ -27,6 +27,11 @@ use buttplug::{
   util::device_configuration::load_protocol_configs,
 };
 use once_cell::sync::OnceCell;
+
+/// Extra setup for the server's device manager, run after the comm managers from [EngineOptions] are
+/// added, right before it's built.
+pub(crate) type DeviceManagerSetup = Box<dyn FnOnce(&mut ServerDeviceManagerBuilder) + Send>;
+
 // Device communication manager setup gets its own module because the includes and platform
 // specifics are such a mess.
 
@@ -96,16 +101,17 @@ pub async fn setup_buttplug_server(
   options: &EngineOptions,
   backdoor_server: &OnceCell<Arc<BackdoorServer>>,
   dcm: &Option<Arc<DeviceConfigurationManager>>,
+  device_manager_setup: Vec<DeviceManagerSetup>,
 ) -> Result<ButtplugRemoteServer, IntifaceEngineError> {
   let mut dm_builder = if let Some(dcm) = dcm {
     ServerDeviceManagerBuilder::new_with_arc(dcm.clone())
   } else {
-    let mut dcm_builder = load_protocol_configs(
-      options.device_config_json(),
-      options.user_device_config_json(),
-      false,
-    )
-    .map_err(|e| IntifaceEngineError::ButtplugError(e.into()))?;
+    let user_config_json = load_user_config_json(options)
+      .await
+      .map_err(IntifaceEngineError::ConfigLoadError)?;
+    let mut dcm_builder =
+      load_protocol_configs(options.device_config_json(), &user_config_json, false)
+        .map_err(|e| IntifaceEngineError::ButtplugError(e.into()))?;
 
     dcm_builder.allow_raw_messages(options.allow_raw_messages());
 
@@ -117,11 +123,14 @@ pub async fn setup_buttplug_server(
   };
 
   setup_server_device_comm_managers(options, &mut dm_builder);
+  for setup in device_manager_setup {
+    setup(&mut dm_builder);
+  }
 
   let mut server_builder = ButtplugServerBuilder::new(
     dm_builder
       .finish()
-      .map_err(|e| IntifaceEngineError::ButtplugServerError(e))?,
+      .map_err(IntifaceEngineError::ButtplugServerError)?,
   );
   server_builder
     .name(options.server_name())
@@ -135,7 +144,7 @@ pub async fn setup_buttplug_server(
     }
   };
   if backdoor_server
-    .set(Arc::new(BackdoorServer::new(core_server.device_manager())))
+    .set(Arc::new(BackdoorServer::new(core_server.device_manager())?))
     .is_err()
   {
     Err(
@@ -169,7 +178,7 @@ pub async fn run_server(
         _,
         ButtplugServerJSONSerializer,
       >::new(
-        ButtplugWebsocketClientTransport::new_insecure_connector(&addr),
+        ButtplugWebsocketClientTransport::new_insecure_connector(addr),
       ))
       .await
   } else {
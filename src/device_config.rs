@@ -0,0 +1,535 @@
+// Device configuration reloading. The base (protocol) config in a DeviceConfigurationManager can't
+// be changed once it's built, but user configs can, so reloads apply user config changes to the
+// running DCM and report base config changes as needing a restart. New protocol specifiers can
+// still be tried out without a restart by adding them to the user config's protocols section.
+
+use crate::{
+  frontend::{EngineMessage, Frontend, IntifaceMessage},
+  options::EngineOptions,
+  user_config::upgrade_user_config_json,
+  IntifaceError,
+};
+use buttplug::{
+  server::device::configuration::{DeviceConfigurationManager, UserDeviceIdentifier},
+  util::device_configuration::load_protocol_configs,
+};
+use getset::{CopyGetters, Getters};
+use serde::{Deserialize, Serialize};
+use std::{
+  collections::BTreeSet,
+  sync::Arc,
+  time::{Duration, SystemTime},
+};
+use tokio::{fs, select, sync::broadcast};
+use tokio_util::sync::CancellationToken;
+
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize, Getters, CopyGetters)]
+pub struct DeviceConfigDiff {
+  /// True if the base device config file has changed. Base config changes only take effect once the
+  /// engine is restarted.
+  #[getset(get_copy = "pub")]
+  requires_restart: bool,
+  #[getset(get = "pub")]
+  user_protocols_changed: Vec<String>,
+  #[getset(get = "pub")]
+  user_devices_added: Vec<UserDeviceIdentifier>,
+  #[getset(get = "pub")]
+  user_devices_removed: Vec<UserDeviceIdentifier>,
+  #[getset(get = "pub")]
+  user_devices_changed: Vec<UserDeviceIdentifier>,
+}
+
+impl DeviceConfigDiff {
+  pub fn is_empty(&self) -> bool {
+    *self == DeviceConfigDiff::default()
+  }
+}
+
+/// Reads the device config from `path`, for when options only give us a path.
+pub(crate) async fn load_device_config_json(path: &str) -> Result<String, IntifaceError> {
+  info!("Loading device config from {}", path);
+  fs::read_to_string(path).await.map_err(|e| {
+    IntifaceError::new(&format!(
+      "Error opening device configuration {}: {:?}",
+      path, e
+    ))
+  })
+}
+
+async fn modified_time(path: &Option<String>) -> Option<SystemTime> {
+  fs::metadata(path.as_ref()?).await.ok()?.modified().ok()
+}
+
+pub(crate) struct DeviceConfigReloader {
+  dcm: Arc<DeviceConfigurationManager>,
+  device_config_path: Option<String>,
+  user_device_config_path: Option<String>,
+  // The base config the DCM was built with. It never changes, but new user configs are checked
+  // against it, and the base config file is compared to it.
+  device_config_json: Option<String>,
+  user_device_config_json: Option<String>,
+  allow_raw_messages: bool,
+  last_modified: (Option<SystemTime>, Option<SystemTime>),
+}
+
+impl DeviceConfigReloader {
+  pub(crate) async fn new(options: &EngineOptions, dcm: Arc<DeviceConfigurationManager>) -> Self {
+    let device_config_path = options.device_config_path().clone();
+    let user_device_config_path = options.user_device_config_path().clone();
+    let last_modified = (
+      modified_time(&device_config_path).await,
+      modified_time(&user_device_config_path).await,
+    );
+    Self {
+      dcm,
+      device_config_path,
+      user_device_config_path,
+      device_config_json: options.device_config_json().clone(),
+      user_device_config_json: options.user_device_config_json().clone(),
+      allow_raw_messages: options.allow_raw_messages(),
+      last_modified,
+    }
+  }
+
+  /// Returns true if either config file has been modified since we last looked.
+  pub(crate) async fn files_changed(&mut self) -> bool {
+    let last_modified = (
+      modified_time(&self.device_config_path).await,
+      modified_time(&self.user_device_config_path).await,
+    );
+    if last_modified != self.last_modified {
+      self.last_modified = last_modified;
+      true
+    } else {
+      false
+    }
+  }
+
+  // Checks the base config file against what the DCM was built with. A new base config that doesn't
+  // load is an error, so it's caught before anyone restarts to pick it up.
+  async fn base_config_changed(&self) -> Result<bool, IntifaceError> {
+    let Some(path) = &self.device_config_path else {
+      return Ok(false);
+    };
+    let json = load_device_config_json(path).await?;
+    let parse = |json: &str| serde_json::from_str::<serde_json::Value>(json).ok();
+    if self
+      .device_config_json
+      .as_deref()
+      .is_some_and(|loaded| loaded == json || parse(loaded) == parse(&json))
+    {
+      return Ok(false);
+    }
+    load_protocol_configs(&Some(json), &None, false)
+      .and_then(|mut builder| builder.finish())
+      .map_err(|e| {
+        IntifaceError::new(&format!("Cannot load device configuration {}: {}", path, e))
+      })?;
+    Ok(true)
+  }
+
+  async fn read_user_config(&self) -> Result<Option<String>, IntifaceError> {
+    let Some(path) = &self.user_device_config_path else {
+      return Ok(self.user_device_config_json.clone());
+    };
+    match fs::read_to_string(path).await {
+      Ok(json) => Ok(Some(json)),
+      Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(None),
+      Err(e) => Err(IntifaceError::new(&format!(
+        "Error reading user device configuration {}: {:?}",
+        path, e
+      ))),
+    }
+  }
+
+  /// Rereads the config files, applies user config changes to the running DCM, and returns what
+  /// changed. If the new configuration doesn't load, nothing is changed.
+  pub(crate) async fn reload(&mut self) -> Result<DeviceConfigDiff, IntifaceError> {
+    let requires_restart = self.base_config_changed().await?;
+    let user_device_config_json = self
+      .read_user_config()
+      .await?
+      .map(|json| upgrade_user_config_json(&json, &self.device_config_json))
+      .transpose()?;
+
+    // Build a whole new DCM, both to validate the new configuration and to get at its user configs.
+    let mut dcm_builder =
+      load_protocol_configs(&self.device_config_json, &user_device_config_json, false)
+        .map_err(|e| IntifaceError::new(&format!("Cannot load device configuration: {}", e)))?;
+    dcm_builder.allow_raw_messages(self.allow_raw_messages);
+    let new_dcm = dcm_builder
+      .finish()
+      .map_err(|e| IntifaceError::new(&format!("Cannot load device configuration: {}", e)))?;
+
+    let mut diff = DeviceConfigDiff {
+      requires_restart,
+      ..Default::default()
+    };
+
+    // User communication specifiers, compared (and replaced) per protocol.
+    let old_specifiers = self.dcm.user_communication_specifiers();
+    let new_specifiers = new_dcm.user_communication_specifiers();
+    let protocols: BTreeSet<String> = old_specifiers
+      .iter()
+      .map(|kv| kv.key().clone())
+      .chain(new_specifiers.iter().map(|kv| kv.key().clone()))
+      .collect();
+    for protocol in protocols {
+      let old = old_specifiers
+        .get(&protocol)
+        .map(|specs| specs.value().clone())
+        .unwrap_or_default();
+      let new = new_specifiers
+        .get(&protocol)
+        .map(|specs| specs.value().clone())
+        .unwrap_or_default();
+      if serde_json::to_value(&old).ok() == serde_json::to_value(&new).ok() {
+        continue;
+      }
+      diff.user_protocols_changed.push(protocol.clone());
+      for specifier in &old {
+        self
+          .dcm
+          .remove_user_communication_specifier(&protocol, specifier);
+      }
+      for specifier in &new {
+        self
+          .dcm
+          .add_user_communication_specifier(&protocol, specifier)
+          .map_err(|e| IntifaceError::new(&format!("Cannot apply user protocol: {}", e)))?;
+      }
+    }
+
+    // User device definitions.
+    let removed: Vec<UserDeviceIdentifier> = self
+      .dcm
+      .user_device_definitions()
+      .iter()
+      .map(|kv| kv.key().clone())
+      .filter(|identifier| !new_dcm.user_device_definitions().contains_key(identifier))
+      .collect();
+    for identifier in removed {
+      self.dcm.remove_user_device_definition(&identifier);
+      diff.user_devices_removed.push(identifier);
+    }
+    for kv in new_dcm.user_device_definitions().iter() {
+      let (identifier, definition) = (kv.key(), kv.value());
+      let changed = match self.dcm.user_device_definitions().get(identifier) {
+        None => {
+          diff.user_devices_added.push(identifier.clone());
+          true
+        }
+        Some(old) => {
+          let changed =
+            serde_json::to_value(old.value()).ok() != serde_json::to_value(definition).ok();
+          if changed {
+            diff.user_devices_changed.push(identifier.clone());
+          }
+          changed
+        }
+      };
+      if changed {
+        self
+          .dcm
+          .add_user_device_definition(identifier, definition)
+          .map_err(|e| IntifaceError::new(&format!("Cannot apply user device config: {}", e)))?;
+      }
+    }
+
+    self.user_device_config_json = user_device_config_json;
+    Ok(diff)
+  }
+}
+
+const DEVICE_CONFIG_WATCH_INTERVAL: Duration = Duration::from_secs(2);
+
+/// Reloads device configuration whenever the frontend asks for it, or (if `watch` is set) whenever
+/// the config files change on disk, reporting the results to the frontend.
+pub(crate) async fn device_config_reload_loop(
+  mut reloader: DeviceConfigReloader,
+  frontend: Option<Arc<dyn Frontend>>,
+  watch: bool,
+  stop_token: CancellationToken,
+) {
+  let mut frontend_receiver = frontend.as_ref().map(|frontend| frontend.event_stream());
+  let mut watch_interval = tokio::time::interval(DEVICE_CONFIG_WATCH_INTERVAL);
+  loop {
+    let reload_requested = async {
+      match &mut frontend_receiver {
+        Some(receiver) => receiver.recv().await,
+        None => std::future::pending().await,
+      }
+    };
+    // Our own user config saves show up as file changes too, so watcher reloads that don't change
+    // anything aren't reported. Frontends always get an answer to their requests though.
+    let (reason, requested) = select! {
+      message = reload_requested => match message {
+        Ok(IntifaceMessage::ReloadDeviceConfig {}) => ("frontend request", true),
+        Ok(_) => continue,
+        Err(broadcast::error::RecvError::Lagged(_)) => continue,
+        Err(broadcast::error::RecvError::Closed) => {
+          frontend_receiver = None;
+          continue;
+        }
+      },
+      _ = watch_interval.tick(), if watch => {
+        if !reloader.files_changed().await {
+          continue;
+        }
+        ("config file change", false)
+      }
+      _ = stop_token.cancelled() => break,
+    };
+    info!("Reloading device configuration ({})", reason);
+    let message = match reloader.reload().await {
+      Ok(diff) if diff.is_empty() && !requested => {
+        debug!("Device config files changed, but nothing needed reloading.");
+        continue;
+      }
+      Ok(diff) => {
+        info!("Device configuration reloaded: {:?}", diff);
+        EngineMessage::DeviceConfigReloaded { diff }
+      }
+      Err(e) => {
+        error!("Device configuration reload failed: {}", e);
+        EngineMessage::DeviceConfigReloadFailed {
+          error: e.to_string(),
+        }
+      }
+    };
+    if let Some(frontend) = &frontend {
+      frontend.send(message).await;
+    }
+  }
+}
+
+#[cfg(test)]
+mod test {
+  use super::*;
+  use crate::options::EngineOptionsBuilder;
+  use buttplug::util::device_configuration::DEVICE_CONFIGURATION_JSON;
+  use serde_json::{json, Value};
+  use std::path::{Path, PathBuf};
+
+  fn test_dir(name: &str) -> PathBuf {
+    let dir = std::env::temp_dir().join(format!("intiface-engine-{}-{}", name, std::process::id()));
+    let _ = std::fs::remove_dir_all(&dir);
+    std::fs::create_dir_all(&dir).unwrap();
+    dir
+  }
+
+  fn user_config(protocols: Value, devices: Value) -> String {
+    json!({
+      "version": { "major": 3, "minor": 0 },
+      "user-configs": { "protocols": protocols, "devices": devices }
+    })
+    .to_string()
+  }
+
+  fn device(address: &str, display_name: &str, index: u32) -> Value {
+    json!({
+      "identifier": { "address": address, "protocol": "lovense", "identifier": "P" },
+      "config": {
+        "name": "Lovense Edge",
+        "features": [],
+        "user-config": {
+          "display-name": display_name,
+          "allow": false,
+          "deny": false,
+          "index": index
+        }
+      }
+    })
+  }
+
+  fn identifier(address: &str) -> UserDeviceIdentifier {
+    UserDeviceIdentifier::new(address, "lovense", &Some("P".to_owned()))
+  }
+
+  // Sets up a reloader the way the engine does, with a DCM built from the current config files.
+  async fn reloader(
+    device_config: Option<&Path>,
+    user_device_config: &Path,
+  ) -> DeviceConfigReloader {
+    let mut builder = EngineOptionsBuilder::default();
+    builder.user_device_config_path(user_device_config.to_str().unwrap());
+    if let Some(path) = device_config {
+      builder
+        .device_config_path(path.to_str().unwrap())
+        .device_config_json(&std::fs::read_to_string(path).unwrap());
+    }
+    let options = builder.finish();
+    let user_device_config_json = std::fs::read_to_string(user_device_config).ok();
+    let dcm = load_protocol_configs(
+      options.device_config_json(),
+      &user_device_config_json,
+      false,
+    )
+    .unwrap()
+    .finish()
+    .unwrap();
+    DeviceConfigReloader::new(&options, Arc::new(dcm)).await
+  }
+
+  #[tokio::test]
+  async fn test_reload_user_devices() {
+    let dir = test_dir("reload-user-devices");
+    let user_config_path = dir.join("user.json");
+    std::fs::write(
+      &user_config_path,
+      user_config(
+        json!({}),
+        json!([device("a", "One", 1), device("b", "Two", 2)]),
+      ),
+    )
+    .unwrap();
+    let mut reloader = reloader(None, &user_config_path).await;
+
+    std::fs::write(
+      &user_config_path,
+      user_config(
+        json!({}),
+        json!([device("a", "Renamed", 1), device("c", "Three", 3)]),
+      ),
+    )
+    .unwrap();
+    let diff = reloader.reload().await.unwrap();
+    assert!(!diff.requires_restart());
+    assert!(diff.user_protocols_changed().is_empty());
+    assert_eq!(diff.user_devices_added(), &vec![identifier("c")]);
+    assert_eq!(diff.user_devices_removed(), &vec![identifier("b")]);
+    assert_eq!(diff.user_devices_changed(), &vec![identifier("a")]);
+
+    let definitions = reloader.dcm.user_device_definitions();
+    assert_eq!(
+      definitions
+        .get(&identifier("a"))
+        .unwrap()
+        .user_config()
+        .display_name(),
+      &Some("Renamed".to_owned())
+    );
+    assert!(definitions.contains_key(&identifier("c")));
+    assert!(!definitions.contains_key(&identifier("b")));
+
+    assert!(reloader.reload().await.unwrap().is_empty());
+    std::fs::remove_dir_all(dir).unwrap();
+  }
+
+  #[tokio::test]
+  async fn test_reload_user_protocols() {
+    let dir = test_dir("reload-user-protocols");
+    let user_config_path = dir.join("user.json");
+    std::fs::write(&user_config_path, user_config(json!({}), json!([]))).unwrap();
+    let mut reloader = reloader(None, &user_config_path).await;
+
+    let serial = json!({
+      "lovense": {
+        "communication": [{
+          "serial": {
+            "port": "COM7",
+            "baud-rate": 115200,
+            "data-bits": 8,
+            "parity": "N",
+            "stop-bits": 1
+          }
+        }]
+      }
+    });
+    std::fs::write(&user_config_path, user_config(serial, json!([]))).unwrap();
+    let diff = reloader.reload().await.unwrap();
+    assert_eq!(diff.user_protocols_changed(), &vec!["lovense".to_owned()]);
+    assert!(reloader
+      .dcm
+      .user_communication_specifiers()
+      .contains_key("lovense"));
+
+    std::fs::write(&user_config_path, user_config(json!({}), json!([]))).unwrap();
+    let diff = reloader.reload().await.unwrap();
+    assert_eq!(diff.user_protocols_changed(), &vec!["lovense".to_owned()]);
+    assert!(reloader
+      .dcm
+      .user_communication_specifiers()
+      .get("lovense")
+      .is_none_or(|specifiers| specifiers.is_empty()));
+    std::fs::remove_dir_all(dir).unwrap();
+  }
+
+  #[tokio::test]
+  async fn test_reload_invalid_user_config() {
+    let dir = test_dir("reload-invalid-user-config");
+    let user_config_path = dir.join("user.json");
+    std::fs::write(
+      &user_config_path,
+      user_config(json!({}), json!([device("a", "One", 1)])),
+    )
+    .unwrap();
+    let mut reloader = reloader(None, &user_config_path).await;
+
+    std::fs::write(&user_config_path, "{ not json").unwrap();
+    assert!(reloader.reload().await.is_err());
+    assert!(reloader
+      .dcm
+      .user_device_definitions()
+      .contains_key(&identifier("a")));
+    std::fs::remove_dir_all(dir).unwrap();
+  }
+
+  #[tokio::test]
+  async fn test_reload_base_config_change() {
+    let dir = test_dir("reload-base-config");
+    let device_config_path = dir.join("device.json");
+    let user_config_path = dir.join("user.json");
+    std::fs::write(&device_config_path, DEVICE_CONFIGURATION_JSON).unwrap();
+    std::fs::write(&user_config_path, user_config(json!({}), json!([]))).unwrap();
+    let mut reloader = reloader(Some(&device_config_path), &user_config_path).await;
+
+    // Formatting changes don't count.
+    let mut base: Value = serde_json::from_str(DEVICE_CONFIGURATION_JSON).unwrap();
+    std::fs::write(&device_config_path, base.to_string()).unwrap();
+    assert!(reloader.reload().await.unwrap().is_empty());
+
+    base["version"]["minor"] = json!(base["version"]["minor"].as_u64().unwrap() + 1);
+    std::fs::write(&device_config_path, base.to_string()).unwrap();
+    std::fs::write(
+      &user_config_path,
+      user_config(json!({}), json!([device("a", "One", 1)])),
+    )
+    .unwrap();
+    let diff = reloader.reload().await.unwrap();
+    assert!(diff.requires_restart());
+    // User config changes still go in.
+    assert_eq!(diff.user_devices_added(), &vec![identifier("a")]);
+
+    std::fs::write(&device_config_path, "{ not json").unwrap();
+    assert!(reloader.reload().await.is_err());
+    std::fs::remove_dir_all(dir).unwrap();
+  }
+
+  #[tokio::test]
+  async fn test_files_changed() {
+    let dir = test_dir("files-changed");
+    let device_config_path = dir.join("device.json");
+    let user_config_path = dir.join("user.json");
+    std::fs::write(&device_config_path, DEVICE_CONFIGURATION_JSON).unwrap();
+    std::fs::write(&user_config_path, user_config(json!({}), json!([]))).unwrap();
+    let mut reloader = reloader(Some(&device_config_path), &user_config_path).await;
+    assert!(!reloader.files_changed().await);
+
+    let set_modified = |path: &Path, secs: u64| {
+      std::fs::File::options()
+        .write(true)
+        .open(path)
+        .unwrap()
+        .set_modified(SystemTime::UNIX_EPOCH + Duration::from_secs(secs))
+        .unwrap();
+    };
+    set_modified(&user_config_path, 1000);
+    assert!(reloader.files_changed().await);
+    assert!(!reloader.files_changed().await);
+    set_modified(&device_config_path, 1000);
+    assert!(reloader.files_changed().await);
+    assert!(!reloader.files_changed().await);
+    std::fs::remove_dir_all(dir).unwrap();
+  }
+}
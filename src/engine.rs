@@ -1,42 +1,119 @@
 use crate::{
-  backdoor_server::BackdoorServer,
-  buttplug_server::{run_server, setup_buttplug_server},
-  error::IntifaceEngineError,
+  backdoor_server::{BackdoorServer, BackdoorWebsocketServer},
+  buttplug_server::{run_server, setup_buttplug_server, DeviceManagerSetup},
+  device_config::{device_config_reload_loop, load_device_config_json, DeviceConfigReloader},
+  error::{IntifaceEngineError, IntifaceError},
+  fault_injection::{FaultInjector, FaultKind},
   frontend::{
-    frontend_external_event_loop, frontend_server_event_loop, process_messages::EngineMessage,
-    Frontend,
+    frontend_external_event_loop, frontend_repeater_event_loop, frontend_server_event_loop,
+    process_messages::EngineMessage, Frontend,
   },
-  mdns::IntifaceMdns,
-  options::EngineOptions,
+  mdns::{discover_engines, IntifaceMdns},
+  options::{EngineOptions, EngineOptionsBuilder, OptionsError},
   remote_server::ButtplugRemoteServerEvent,
+  replay_repeater_session,
+  user_config::{
+    user_config_command_loop, UserConfigPersistence, DEFAULT_USER_CONFIG_BACKUP_COUNT,
+  },
   ButtplugRepeater,
 };
 
-use buttplug::{
-  server::device::configuration::DeviceConfigurationManager,
-  util::device_configuration::save_user_config,
+use buttplug::server::device::{
+  configuration::DeviceConfigurationManager,
+  hardware::communication::HardwareCommunicationManagerBuilder, ServerDeviceManagerBuilder,
 };
 use futures::{pin_mut, StreamExt};
 use once_cell::sync::OnceCell;
-use std::{path::Path, sync::Arc, time::Duration};
-use tokio::{fs, select};
+use std::{
+  sync::{Arc, Mutex},
+  time::Duration,
+};
+use tokio::select;
 use tokio_util::sync::CancellationToken;
 
-#[cfg(debug_assertions)]
-pub fn maybe_crash_main_thread(options: &EngineOptions) {
-  if options.crash_main_thread() {
-    panic!("Crashing main thread by request");
+const MDNS_DISCOVERY_TIME: Duration = Duration::from_secs(3);
+
+// Repeater upstreams can either be given directly, or looked up via mDNS by instance name.
+async fn repeater_remote_address(options: &EngineOptions) -> Result<String, IntifaceEngineError> {
+  let Some(instance_name) = options.repeater_remote_mdns_name() else {
+    return options
+      .repeater_remote_address()
+      .clone()
+      .ok_or_else(|| vec![OptionsError::MissingRepeaterRemote {}].into());
+  };
+  info!("Looking up repeater upstream {} via mDNS", instance_name);
+  let engines = discover_engines(MDNS_DISCOVERY_TIME)
+    .await
+    .map_err(IntifaceEngineError::MdnsError)?;
+  engines
+    .iter()
+    .find(|engine| engine.instance_name() == instance_name)
+    .and_then(|engine| engine.websocket_address())
+    .ok_or_else(|| {
+      IntifaceEngineError::MdnsError(IntifaceError::new(&format!(
+        "Cannot find engine {} via mDNS for repeater upstream",
+        instance_name
+      )))
+    })
+}
+
+// Tells the frontend why the engine is going down, then stops it the same way a clean exit does, so
+// it's never left hanging on a connection that just drops.
+async fn stop_with_error(
+  frontend: &Option<Arc<dyn Frontend>>,
+  error: IntifaceEngineError,
+) -> IntifaceEngineError {
+  error!("Engine stopped with error: {}", error);
+  if let Some(frontend) = frontend {
+    frontend.send(EngineMessage::from(&error)).await;
+    frontend.send(EngineMessage::EngineStopped {}).await;
+    tokio::time::sleep(Duration::from_millis(100)).await;
+    frontend.disconnect();
   }
+  error
 }
 
-#[allow(dead_code)]
-#[cfg(debug_assertions)]
-pub fn maybe_crash_task_thread(options: &EngineOptions) {
-  if options.crash_task_thread() {
-    tokio::spawn(async {
-      tokio::time::sleep(Duration::from_millis(100)).await;
-      panic!("Crashing a task thread by request");
-    });
+/// Builds an [IntifaceEngine] with device manager setup that [EngineOptions] can't express, like
+/// comm managers that don't ship with buttplug. Engines built with [IntifaceEngine::default] only
+/// get what the options ask for.
+#[derive(Default)]
+pub struct IntifaceEngineBuilder {
+  comm_managers: Vec<DeviceManagerSetup>,
+  device_manager_customizers: Vec<DeviceManagerSetup>,
+}
+
+impl IntifaceEngineBuilder {
+  /// Adds a comm manager to the server, alongside the ones turned on in [EngineOptions].
+  pub fn comm_manager<T>(&mut self, builder: T) -> &mut Self
+  where
+    T: HardwareCommunicationManagerBuilder + 'static,
+  {
+    self.comm_managers.push(Box::new(
+      move |dm_builder: &mut ServerDeviceManagerBuilder| {
+        dm_builder.comm_manager(builder);
+      },
+    ));
+    self
+  }
+
+  /// Runs `setup` on the server's device manager builder right before it's built, after all comm
+  /// managers (including ones from [IntifaceEngineBuilder::comm_manager]) have been added. If there's
+  /// more than one, they run in the order they were added.
+  pub fn customize_device_manager<F>(&mut self, setup: F) -> &mut Self
+  where
+    F: FnOnce(&mut ServerDeviceManagerBuilder) + Send + 'static,
+  {
+    self.device_manager_customizers.push(Box::new(setup));
+    self
+  }
+
+  pub fn finish(&mut self) -> IntifaceEngine {
+    let mut device_manager_setup = std::mem::take(&mut self.comm_managers);
+    device_manager_setup.append(&mut self.device_manager_customizers);
+    IntifaceEngine {
+      device_manager_setup: Mutex::new(device_manager_setup),
+      ..Default::default()
+    }
   }
 }
 
@@ -44,6 +121,9 @@ pub fn maybe_crash_task_thread(options: &EngineOptions) {
 pub struct IntifaceEngine {
   stop_token: Arc<CancellationToken>,
   backdoor_server: OnceCell<Arc<BackdoorServer>>,
+  // Comm managers, then customizers. Taken by the first run, since the server can only be set up
+  // once anyway.
+  device_manager_setup: Mutex<Vec<DeviceManagerSetup>>,
 }
 
 impl IntifaceEngine {
@@ -68,33 +148,82 @@ impl IntifaceEngine {
       frontend.send(EngineMessage::EngineStarted {}).await;
     }
 
-    // Set up mDNS
-    let _mdns_server = if options.broadcast_server_mdns() {
-      // TODO Unregister whenever we have a live connection
+    // Check options before we bring anything up, so bad configurations are reported instead of
+    // blowing up somewhere down the line.
+    if let Err(errors) = options.validate() {
+      for error in &errors {
+        error!("Invalid engine options: {}", error);
+      }
+      if let Some(frontend) = &frontend {
+        frontend
+          .send(EngineMessage::EngineOptionsInvalid {
+            errors: errors.clone(),
+          })
+          .await;
+        frontend.send(EngineMessage::EngineStopped {}).await;
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        frontend.disconnect();
+      }
+      return Err(errors.into());
+    }
 
-      // TODO Support different services for engine versus repeater
-      Some(IntifaceMdns::new())
+    // Set up mDNS
+    let mdns_result = if !options.broadcast_server_mdns() {
+      None
+    } else if options.repeater_mode() {
+      // Replays don't listen for clients, so there's nothing to advertise.
+      options
+        .repeater_local_port()
+        .filter(|_| options.repeater_replay_path().is_none())
+        .map(|port| IntifaceMdns::new_repeater(options, port))
+    } else if let Some(port) = options.websocket_port() {
+      Some(IntifaceMdns::new_engine(options, port))
     } else {
+      warn!("mDNS broadcast requested, but no websocket port is set. Not advertising.");
       None
     };
+    // Failing to advertise shouldn't take the whole engine down, so just warn and keep going.
+    let mut mdns_server = match mdns_result {
+      Some(Ok(mdns)) => Some(mdns),
+      Some(Err(e)) => {
+        warn!("mDNS advertisement failed, continuing without it: {}", e);
+        if let Some(frontend) = &frontend {
+          frontend
+            .send(EngineMessage::EngineWarning {
+              warning: format!("mDNS advertisement failed: {}", e),
+            })
+            .await;
+        }
+        None
+      }
+      None => None,
+    };
 
     // Set up Repeater (if in repeater mode)
     if options.repeater_mode() {
-      info!("Starting repeater");
-
-      let repeater = ButtplugRepeater::new(
-        options.repeater_local_port().unwrap(),
-        &options.repeater_remote_address().as_ref().unwrap(),
-        self.stop_token.child_token(),
-      );
-      select! {
-        _ = self.stop_token.cancelled() => {
-          info!("Owner requested process exit, exiting.");
-        }
-        _ = repeater.listen() => {
-          info!("Repeater listener stopped, exiting.");
+      let result = if let Some(replay_path) = options.repeater_replay_path() {
+        info!("Starting repeater session replay");
+        let remote_address = match repeater_remote_address(options).await {
+          Ok(remote_address) => remote_address,
+          Err(e) => return Err(stop_with_error(&frontend, e).await),
+        };
+        select! {
+          _ = self.stop_token.cancelled() => {
+            info!("Owner requested process exit, exiting.");
+            Ok(())
+          }
+          result = replay_repeater_session(replay_path, &remote_address) => {
+            result.map_err(IntifaceEngineError::RepeaterError)
+          }
         }
+      } else {
+        self
+          .run_repeater(options, &frontend, mdns_server.take())
+          .await
       };
+      if let Err(e) = result {
+        return Err(stop_with_error(&frontend, e).await);
+      }
       if let Some(frontend) = &frontend {
         frontend.send(EngineMessage::EngineStopped {}).await;
         tokio::time::sleep(Duration::from_millis(100)).await;
@@ -105,42 +234,125 @@ impl IntifaceEngine {
 
     // Set up Engine (if in engine mode)
 
-    // At this point we will have received and validated options.
+    // If we were only given a path to the device config, load it now, so the server, user config
+    // upgrades and reloads all see the same base config.
+    let loaded_options;
+    let options = match (options.device_config_json(), options.device_config_path()) {
+      (None, Some(path)) => {
+        let json = match load_device_config_json(path).await {
+          Ok(json) => json,
+          Err(e) => {
+            return Err(stop_with_error(&frontend, IntifaceEngineError::ConfigLoadError(e)).await)
+          }
+        };
+        loaded_options = EngineOptionsBuilder::from(options.clone())
+          .device_config_json(&json)
+          .finish();
+        &loaded_options
+      }
+      _ => options,
+    };
+
+    // Bind the backdoor websocket before anything's spawned, so if the port's taken we can just bail.
+    let backdoor_websocket = match options.backdoor_websocket_port() {
+      Some(port) => {
+        let token = options.backdoor_token().clone().unwrap_or_default();
+        match BackdoorWebsocketServer::bind(port, &token).await {
+          Ok(backdoor_websocket) => Some(backdoor_websocket),
+          Err(e) => {
+            return Err(stop_with_error(&frontend, IntifaceEngineError::BindError(e)).await)
+          }
+        }
+      }
+      None => None,
+    };
 
     // Hang out until those listeners get sick of listening.
     info!("Intiface CLI Setup finished, running server tasks until all joined.");
-    let server = setup_buttplug_server(options, &self.backdoor_server, &dcm).await?;
+    // The clock on injected faults starts here. Device manager stalls have to go in while the server
+    // is being built, everything else is kicked off once it's all running.
+    let mut fault_injector = FaultInjector::new(options);
+    // Injected faults only add comm managers, so they go in ahead of any customizers.
+    let mut device_manager_setup: Vec<DeviceManagerSetup> =
+      fault_injector.device_manager_setup().into_iter().collect();
+    device_manager_setup.append(&mut self.device_manager_setup.lock().unwrap());
+    let server = match setup_buttplug_server(
+      options,
+      &self.backdoor_server,
+      dcm,
+      device_manager_setup,
+    )
+    .await
+    {
+      Ok(server) => server,
+      Err(e) => return Err(stop_with_error(&frontend, e).await),
+    };
     let dcm = server
       .server()
       .device_manager()
       .device_configuration_manager()
       .clone();
-    if let Some(config_path) = options.user_device_config_path() {
+    if frontend.is_some() || options.watch_device_config() {
+      let reloader = DeviceConfigReloader::new(options, dcm.clone()).await;
+      let frontend_clone = frontend.clone();
+      let watch = options.watch_device_config();
+      let stop_child_token = self.stop_token.child_token();
+      tokio::spawn(async move {
+        device_config_reload_loop(reloader, frontend_clone, watch, stop_child_token).await;
+      });
+    }
+    let user_config_persistence = options.user_device_config_path().as_ref().map(|path| {
+      let persistence = UserConfigPersistence::new(
+        path,
+        options
+          .user_device_config_backup_count()
+          .unwrap_or(DEFAULT_USER_CONFIG_BACKUP_COUNT),
+        dcm.clone(),
+        frontend.clone(),
+      );
+      let saver = persistence.saver();
       let stream = server.event_stream();
-      {
-        let config_path = config_path.to_owned();
-        tokio::spawn(async move {
-          pin_mut!(stream);
-          loop {
-            if let Some(event) = stream.next().await {
-              match event {
-                ButtplugRemoteServerEvent::DeviceAdded {
-                  index: _,
-                  identifier: _,
-                  name: _,
-                  display_name: _,
-                } => {
-                  if let Ok(config_str) = save_user_config(&dcm) {
-                    // Should probably at least log if we fail to write the config file
-                    let _ = fs::write(&Path::new(&config_path), config_str).await;
-                  }
-                }
-                _ => continue,
-              }
-            };
+      let stop_child_token = self.stop_token.child_token();
+      tokio::spawn(async move {
+        pin_mut!(stream);
+        loop {
+          select! {
+            event = stream.next() => match event {
+              Some(ButtplugRemoteServerEvent::DeviceAdded { .. })
+              | Some(ButtplugRemoteServerEvent::DeviceRemoved { .. }) => saver.request_save(),
+              Some(_) => continue,
+              None => break,
+            },
+            _ = stop_child_token.cancelled() => break,
           }
-        });
-      }
+        }
+      });
+      persistence
+    });
+    if let Some(frontend) = &frontend {
+      let dcm = dcm.clone();
+      let frontend_clone = frontend.clone();
+      let saver = user_config_persistence.as_ref().map(|p| p.saver());
+      let stop_child_token = self.stop_token.child_token();
+      tokio::spawn(async move {
+        user_config_command_loop(dcm, frontend_clone, saver, stop_child_token).await;
+      });
+    }
+    if let (Some(backdoor_websocket), Some(backdoor)) = (backdoor_websocket, self.backdoor_server())
+    {
+      let stop_child_token = self.stop_token.child_token();
+      tokio::spawn(async move {
+        backdoor_websocket.run(backdoor, stop_child_token).await;
+      });
+    }
+    if let Some(mdns_server) = mdns_server.take() {
+      let event_receiver = server.event_stream();
+      let stop_child_token = self.stop_token.child_token();
+      tokio::spawn(async move {
+        mdns_server
+          .track_server_events(event_receiver, stop_child_token)
+          .await;
+      });
     }
     if let Some(frontend) = &frontend {
       frontend.send(EngineMessage::EngineServerCreated {}).await;
@@ -152,30 +364,32 @@ impl IntifaceEngine {
       });
     }
 
+    fault_injector.spawn_task_faults();
+
     loop {
       let session_connection_token = CancellationToken::new();
       info!("Starting server");
 
-      // Let everything spin up, then try crashing.
-
-      #[cfg(debug_assertions)]
-      maybe_crash_main_thread(options);
-
       let mut exit_requested = false;
       select! {
         _ = self.stop_token.cancelled() => {
           info!("Owner requested process exit, exiting.");
           exit_requested = true;
         }
+        fault = fault_injector.next_server_fault() => {
+          match fault {
+            FaultKind::PanicMainThread => panic!("Crashing main thread by request"),
+            _ => warn!("Dropping client connection by request"),
+          }
+        }
         result = run_server(&server, options) => {
           match result {
             Ok(_) => info!("Connection dropped, restarting stay open loop."),
             Err(e) => {
-              error!("{}", format!("Process Error: {:?}", e));
+              let e = IntifaceEngineError::from(e);
+              error!("Process Error: {}", e);
               if let Some(frontend) = &frontend {
-                frontend
-                  .send(EngineMessage::EngineError{ error: format!("Process Error: {:?}", e).to_owned()})
-                  .await;
+                frontend.send(EngineMessage::from(&e)).await;
               }
               exit_requested = true;
             }
@@ -202,6 +416,9 @@ impl IntifaceEngine {
     if let Err(e) = server.shutdown().await {
       error!("Shutdown failed: {:?}", e);
     }
+    if let Some(persistence) = user_config_persistence {
+      persistence.shutdown().await;
+    }
     info!("Exiting");
     if let Some(frontend) = &frontend {
       frontend.send(EngineMessage::EngineStopped {}).await;
@@ -211,8 +428,142 @@ impl IntifaceEngine {
     Ok(())
   }
 
+  async fn run_repeater(
+    &self,
+    options: &EngineOptions,
+    frontend: &Option<Arc<dyn Frontend>>,
+    mdns_server: Option<IntifaceMdns>,
+  ) -> Result<(), IntifaceEngineError> {
+    info!("Starting repeater");
+
+    let mut remote_addresses = vec![repeater_remote_address(options).await?];
+    remote_addresses.extend(options.repeater_fan_out_addresses().iter().cloned());
+    let local_port = options
+      .repeater_local_port()
+      .ok_or_else(|| IntifaceEngineError::from(vec![OptionsError::MissingRepeaterLocalPort {}]))?;
+    let mut repeater =
+      ButtplugRepeater::new_fan_out(local_port, &remote_addresses, self.stop_token.child_token());
+    if let (Some(cert_path), Some(key_path)) = (
+      options.repeater_tls_cert_path(),
+      options.repeater_tls_key_path(),
+    ) {
+      repeater
+        .use_tls(cert_path, key_path)
+        .map_err(IntifaceEngineError::RepeaterError)?;
+    }
+    repeater.listen_on_all_interfaces(options.websocket_use_all_interfaces());
+    if let Some(directory) = options.repeater_record_directory() {
+      repeater.record_sessions(directory);
+    }
+    if let Some(frontend) = frontend {
+      let event_receiver = repeater.event_stream();
+      let frontend_clone = frontend.clone();
+      let stop_child_token = self.stop_token.child_token();
+      tokio::spawn(async move {
+        frontend_repeater_event_loop(event_receiver, frontend_clone, stop_child_token).await;
+      });
+    }
+    if let Some(mdns_server) = mdns_server {
+      let event_receiver = repeater.event_stream();
+      let stop_child_token = self.stop_token.child_token();
+      tokio::spawn(async move {
+        mdns_server
+          .track_repeater_events(event_receiver, stop_child_token)
+          .await;
+      });
+    }
+    select! {
+      _ = self.stop_token.cancelled() => {
+        info!("Owner requested process exit, exiting.");
+      }
+      result = repeater.listen() => {
+        result.map_err(IntifaceEngineError::BindError)?;
+        info!("Repeater listener stopped, exiting.");
+      }
+    };
+    Ok(())
+  }
+
   pub fn stop(&self) {
     info!("Engine stop called, cancelling token.");
     self.stop_token.cancel();
   }
 }
+
+#[cfg(test)]
+mod test {
+  use super::*;
+  use crate::{error::IntifaceError, frontend::IntifaceMessage};
+  use async_trait::async_trait;
+  use tokio::{
+    net::TcpListener,
+    sync::{broadcast, Notify},
+  };
+
+  #[derive(Default)]
+  struct RecordingFrontend {
+    messages: Mutex<Vec<EngineMessage>>,
+    notify: Arc<Notify>,
+  }
+
+  #[async_trait]
+  impl Frontend for RecordingFrontend {
+    async fn send(&self, msg: EngineMessage) {
+      self.messages.lock().unwrap().push(msg);
+    }
+    async fn connect(&self) -> Result<(), IntifaceError> {
+      Ok(())
+    }
+    fn disconnect(&self) {
+      self.notify.notify_waiters();
+    }
+    fn disconnect_notifier(&self) -> Arc<Notify> {
+      self.notify.clone()
+    }
+    fn event_stream(&self) -> broadcast::Receiver<IntifaceMessage> {
+      let (_, receiver) = broadcast::channel(255);
+      receiver
+    }
+  }
+
+  async fn assert_stops_with_error(options: &EngineOptions, code: &str) {
+    let frontend = Arc::new(RecordingFrontend::default());
+    let result = IntifaceEngine::default()
+      .run(options, Some(frontend.clone()), &None)
+      .await;
+    assert_eq!(result.unwrap_err().code(), code);
+    let messages = frontend.messages.lock().unwrap();
+    assert!(
+      matches!(
+        messages.as_slice(),
+        [
+          EngineMessage::EngineStarted {},
+          EngineMessage::EngineError { code: sent_code, .. },
+          EngineMessage::EngineStopped {},
+        ] if sent_code == code
+      ),
+      "{:?}",
+      messages
+    );
+  }
+
+  #[tokio::test]
+  async fn test_device_config_load_failure_stops_frontend() {
+    let options = EngineOptionsBuilder::default()
+      .websocket_port(12345)
+      .device_config_path("/nonexistent/buttplug-device-config.json")
+      .finish();
+    assert_stops_with_error(&options, "config_load_failed").await;
+  }
+
+  #[tokio::test]
+  async fn test_backdoor_bind_failure_stops_frontend() {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let options = EngineOptionsBuilder::default()
+      .websocket_port(12345)
+      .backdoor_websocket_port(listener.local_addr().unwrap().port())
+      .backdoor_token("token")
+      .finish();
+    assert_stops_with_error(&options, "bind_failed").await;
+  }
+}
@@ -1,5 +1,10 @@
+use crate::{
+  backdoor_server::BackdoorServerError, options::OptionsError,
+  remote_server::ButtplugServerConnectorError,
+};
 use buttplug::{core::errors::ButtplugError, server::ButtplugServerError};
 use std::{error::Error, fmt};
+use thiserror::Error;
 
 #[derive(Debug)]
 pub struct IntifaceError {
@@ -26,28 +31,141 @@ impl Error for IntifaceError {
   }
 }
 
-#[derive(Debug)]
+fn join_errors(errors: &[OptionsError]) -> String {
+  errors
+    .iter()
+    .map(|e| e.to_string())
+    .collect::<Vec<_>>()
+    .join("; ")
+}
+
+#[derive(Error, Debug)]
 pub enum IntifaceEngineError {
-  IoError(std::io::Error),
-  ButtplugServerError(ButtplugServerError),
-  ButtplugError(ButtplugError),
-  IntifaceError(IntifaceError),
+  #[error("IO error: {0}")]
+  IoError(#[from] std::io::Error),
+  #[error("Buttplug server error: {0}")]
+  ButtplugServerError(#[from] ButtplugServerError),
+  #[error("Buttplug error: {0}")]
+  ButtplugError(#[from] ButtplugError),
+  #[error("Server connection error: {0}")]
+  ServerConnectorError(#[from] ButtplugServerConnectorError),
+  #[error("Backdoor error: {0}")]
+  BackdoorError(#[from] BackdoorServerError),
+  #[error("{0}")]
+  IntifaceError(#[from] IntifaceError),
+  #[error("{0}")]
+  BindError(IntifaceError),
+  #[error("{0}")]
+  ConfigLoadError(IntifaceError),
+  #[error("{0}")]
+  MdnsError(IntifaceError),
+  #[error("{0}")]
+  RepeaterError(IntifaceError),
+  #[error("Invalid engine options: {}", join_errors(.0))]
+  OptionsError(Vec<OptionsError>),
 }
 
-impl From<std::io::Error> for IntifaceEngineError {
-  fn from(err: std::io::Error) -> Self {
-    IntifaceEngineError::IoError(err)
+impl IntifaceEngineError {
+  /// Machine readable error code, for frontends to localize or react to errors. These are stable, so
+  /// new variants get new codes, and existing codes never change meaning.
+  pub fn code(&self) -> &'static str {
+    match self {
+      IntifaceEngineError::IoError(_) => "io_error",
+      IntifaceEngineError::ButtplugServerError(_) => "buttplug_server_error",
+      IntifaceEngineError::ButtplugError(_) => "buttplug_error",
+      IntifaceEngineError::ServerConnectorError(_) => "server_connector_error",
+      IntifaceEngineError::BackdoorError(_) => "backdoor_error",
+      IntifaceEngineError::IntifaceError(_) => "engine_error",
+      IntifaceEngineError::BindError(_) => "bind_failed",
+      IntifaceEngineError::ConfigLoadError(_) => "config_load_failed",
+      IntifaceEngineError::MdnsError(_) => "mdns_error",
+      IntifaceEngineError::RepeaterError(_) => "repeater_error",
+      IntifaceEngineError::OptionsError(_) => "invalid_options",
+    }
+  }
+
+  /// Everything below this error in the `source()` chain, outermost first, for logging and bug
+  /// reports. The immediate source is already part of this error's message, so it's skipped.
+  pub fn details(&self) -> Vec<String> {
+    let mut details = vec![];
+    let mut source = self.source().and_then(|error| error.source());
+    while let Some(error) = source {
+      details.push(error.to_string());
+      source = error.source();
+    }
+    details
   }
 }
 
-impl From<ButtplugError> for IntifaceEngineError {
-  fn from(err: ButtplugError) -> Self {
-    IntifaceEngineError::ButtplugError(err)
+impl From<Vec<OptionsError>> for IntifaceEngineError {
+  fn from(err: Vec<OptionsError>) -> Self {
+    IntifaceEngineError::OptionsError(err)
   }
 }
 
-impl From<IntifaceError> for IntifaceEngineError {
-  fn from(err: IntifaceError) -> Self {
-    IntifaceEngineError::IntifaceError(err)
+#[cfg(test)]
+mod test {
+  use super::*;
+  use crate::options::OptionsError;
+  use std::io;
+
+  #[derive(Error, Debug)]
+  #[error("Cannot read config: {0}")]
+  struct ReadError(#[source] IntifaceError);
+
+  #[test]
+  fn test_codes() {
+    let error = || IntifaceError::new("test");
+    let cases = [
+      (
+        IntifaceEngineError::from(io::Error::other("test")),
+        "io_error",
+      ),
+      (IntifaceEngineError::from(error()), "engine_error"),
+      (IntifaceEngineError::BindError(error()), "bind_failed"),
+      (
+        IntifaceEngineError::ConfigLoadError(error()),
+        "config_load_failed",
+      ),
+      (IntifaceEngineError::MdnsError(error()), "mdns_error"),
+      (
+        IntifaceEngineError::RepeaterError(error()),
+        "repeater_error",
+      ),
+      (
+        IntifaceEngineError::from(BackdoorServerError::NotRunning),
+        "backdoor_error",
+      ),
+      (
+        IntifaceEngineError::from(vec![OptionsError::MissingBackdoorToken {}]),
+        "invalid_options",
+      ),
+    ];
+    for (error, code) in cases {
+      assert_eq!(error.code(), code, "{}", error);
+    }
+  }
+
+  #[test]
+  fn test_details_skip_the_source_in_the_message() {
+    let error = IntifaceEngineError::from(io::Error::new(io::ErrorKind::NotFound, "missing"));
+    assert_eq!(error.to_string(), "IO error: missing");
+    assert!(error.details().is_empty());
+
+    let error = IntifaceEngineError::BindError(IntifaceError::new("Cannot bind"));
+    assert_eq!(error.to_string(), "Cannot bind");
+    assert!(error.details().is_empty());
+  }
+
+  #[test]
+  fn test_details_list_the_rest_of_the_chain() {
+    let error = IntifaceEngineError::from(io::Error::other(ReadError(IntifaceError::new(
+      "permission denied",
+    ))));
+    assert_eq!(
+      error.to_string(),
+      "IO error: Cannot read config: permission denied"
+    );
+    assert_eq!(error.details(), vec!["permission denied".to_owned()]);
   }
 }
@@ -0,0 +1,366 @@
+// Fault injection, for testing supervisors and frontends against an engine that misbehaves. Faults
+// can always be described in options, but they're only ever triggered when the engine is built with
+// the `fault-injection` feature.
+
+use crate::{buttplug_server::DeviceManagerSetup, options::EngineOptions, IntifaceError};
+use async_trait::async_trait;
+use buttplug::{
+  core::{errors::ButtplugDeviceError, ButtplugResultFuture},
+  server::device::{
+    configuration::ProtocolCommunicationSpecifier,
+    hardware::{
+      communication::{
+        HardwareCommunicationManager, HardwareCommunicationManagerBuilder,
+        HardwareCommunicationManagerEvent,
+      },
+      HardwareConnector, HardwareSpecializer,
+    },
+    ServerDeviceManagerBuilder,
+  },
+};
+use futures::{future, FutureExt};
+use getset::CopyGetters;
+use serde::{Deserialize, Serialize};
+use std::{fmt, str::FromStr, time::Duration};
+use tokio::{
+  runtime::{Handle, RuntimeFlavor},
+  sync::mpsc::Sender,
+  time::Instant,
+};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum FaultKind {
+  /// Panic the thread running the engine's server loop.
+  PanicMainThread,
+  /// Panic a spawned task, for testing panic logging/reporting.
+  PanicTask,
+  /// Disconnect the currently connected client, as if the connection dropped.
+  DropClientConnection,
+  /// Block the device manager's event loop, so devices stop being added or removed and scanning
+  /// requests go unanswered. With the CLI's current thread runtime, this hangs the whole engine.
+  /// Doesn't trigger if the user config has an allow list, since the device manager never gets as
+  /// far as looking at our fake device then.
+  StallDeviceManager,
+}
+
+impl FaultKind {
+  fn name(&self) -> &'static str {
+    match self {
+      FaultKind::PanicMainThread => "panic-main-thread",
+      FaultKind::PanicTask => "panic-task",
+      FaultKind::DropClientConnection => "drop-client-connection",
+      FaultKind::StallDeviceManager => "stall-device-manager",
+    }
+  }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, CopyGetters)]
+pub struct InjectedFault {
+  #[getset(get_copy = "pub")]
+  kind: FaultKind,
+  /// Seconds after the engine starts to trigger the fault.
+  #[serde(default)]
+  #[getset(get_copy = "pub")]
+  after_secs: u64,
+}
+
+impl InjectedFault {
+  pub fn new(kind: FaultKind, after_secs: u64) -> Self {
+    Self { kind, after_secs }
+  }
+}
+
+impl fmt::Display for InjectedFault {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    write!(f, "{}@{}", self.kind.name(), self.after_secs)
+  }
+}
+
+/// Parses faults in the form `kind[@seconds]`, e.g. `panic-task@10` or `drop-client-connection`.
+impl FromStr for InjectedFault {
+  type Err = IntifaceError;
+
+  fn from_str(s: &str) -> Result<Self, Self::Err> {
+    let (kind, after_secs) = match s.split_once('@') {
+      Some((kind, secs)) => (
+        kind,
+        secs
+          .parse()
+          .map_err(|_| IntifaceError::new(&format!("Invalid fault delay in {}", s)))?,
+      ),
+      None => (s, 0),
+    };
+    let kind = [
+      FaultKind::PanicMainThread,
+      FaultKind::PanicTask,
+      FaultKind::DropClientConnection,
+      FaultKind::StallDeviceManager,
+    ]
+    .into_iter()
+    .find(|k| k.name() == kind)
+    .ok_or_else(|| IntifaceError::new(&format!("Unknown fault {}", kind)))?;
+    Ok(Self { kind, after_secs })
+  }
+}
+
+pub(crate) struct FaultInjector {
+  start: Instant,
+  faults: Vec<InjectedFault>,
+}
+
+impl FaultInjector {
+  pub(crate) fn new(options: &EngineOptions) -> Self {
+    let mut faults = vec![];
+    if cfg!(feature = "fault-injection") {
+      faults.extend(options.injected_faults().iter().copied());
+      if options.crash_main_thread() {
+        faults.push(InjectedFault::new(FaultKind::PanicMainThread, 0));
+      }
+      if options.crash_task_thread() {
+        faults.push(InjectedFault::new(FaultKind::PanicTask, 0));
+      }
+    }
+    for fault in &faults {
+      warn!("Fault injection armed: {}", fault);
+    }
+    Self {
+      start: Instant::now(),
+      faults,
+    }
+  }
+
+  fn deadline(&self, fault: &InjectedFault) -> Instant {
+    self.start + Duration::from_secs(fault.after_secs)
+  }
+
+  /// Takes any device manager stalls, returning the setup needed to trigger them from inside the
+  /// device manager.
+  pub(crate) fn device_manager_setup(&mut self) -> Option<DeviceManagerSetup> {
+    let deadline = self
+      .faults
+      .iter()
+      .filter(|fault| fault.kind == FaultKind::StallDeviceManager)
+      .map(|fault| self.deadline(fault))
+      .min()?;
+    self
+      .faults
+      .retain(|fault| fault.kind != FaultKind::StallDeviceManager);
+    Some(Box::new(move |builder: &mut ServerDeviceManagerBuilder| {
+      builder.comm_manager(StallCommunicationManagerBuilder { deadline });
+    }))
+  }
+
+  /// Spawns the faults that don't need anything from the server loop.
+  pub(crate) fn spawn_task_faults(&mut self) {
+    let start = self.start;
+    self.faults.retain(|fault| {
+      let deadline = start + Duration::from_secs(fault.after_secs);
+      match fault.kind {
+        FaultKind::PanicTask => {
+          tokio::spawn(async move {
+            tokio::time::sleep_until(deadline).await;
+            panic!("Crashing a task thread by request");
+          });
+          false
+        }
+        _ => true,
+      }
+    });
+  }
+
+  /// Waits until the next fault the server loop needs to act on is due, and returns it. Never
+  /// resolves if there are none left.
+  pub(crate) async fn next_server_fault(&mut self) -> FaultKind {
+    let Some((index, fault)) = self
+      .faults
+      .iter()
+      .enumerate()
+      .min_by_key(|(_, fault)| fault.after_secs)
+      .map(|(index, fault)| (index, *fault))
+    else {
+      return std::future::pending().await;
+    };
+    tokio::time::sleep_until(self.deadline(&fault)).await;
+    self.faults.remove(index);
+    fault.kind
+  }
+}
+
+// Device manager stalls work by reporting a device when the fault is due. The device manager's event
+// loop asks the device for its specifier as soon as it's found, and that's where we block.
+struct StallCommunicationManagerBuilder {
+  deadline: Instant,
+}
+
+impl HardwareCommunicationManagerBuilder for StallCommunicationManagerBuilder {
+  fn finish(
+    &mut self,
+    sender: Sender<HardwareCommunicationManagerEvent>,
+  ) -> Box<dyn HardwareCommunicationManager> {
+    let deadline = self.deadline;
+    tokio::spawn(async move {
+      tokio::time::sleep_until(deadline).await;
+      warn!("Stalling device manager by request");
+      let _ = sender
+        .send(HardwareCommunicationManagerEvent::DeviceFound {
+          name: "Fault Injection Device".to_owned(),
+          address: "fault-injection-stall".to_owned(),
+          creator: Box::new(StallConnector {}),
+        })
+        .await;
+    });
+    Box::new(StallCommunicationManager {})
+  }
+}
+
+struct StallCommunicationManager {}
+
+impl HardwareCommunicationManager for StallCommunicationManager {
+  fn name(&self) -> &'static str {
+    "FaultInjectionCommunicationManager"
+  }
+
+  fn start_scanning(&mut self) -> ButtplugResultFuture {
+    future::ready(Ok(())).boxed()
+  }
+
+  fn stop_scanning(&mut self) -> ButtplugResultFuture {
+    future::ready(Ok(())).boxed()
+  }
+
+  fn can_scan(&self) -> bool {
+    false
+  }
+}
+
+#[derive(Debug)]
+struct StallConnector {}
+
+#[async_trait]
+impl HardwareConnector for StallConnector {
+  fn specifier(&self) -> ProtocolCommunicationSpecifier {
+    let stall = || loop {
+      std::thread::sleep(Duration::from_secs(3600));
+    };
+    // On a multi thread runtime, only hold up the device manager's task, not everything else that
+    // happens to be scheduled on the same worker.
+    if Handle::current().runtime_flavor() == RuntimeFlavor::MultiThread {
+      tokio::task::block_in_place(stall)
+    } else {
+      stall()
+    }
+  }
+
+  async fn connect(&mut self) -> Result<Box<dyn HardwareSpecializer>, ButtplugDeviceError> {
+    Err(ButtplugDeviceError::DeviceConnectionError(
+      "Fault injection devices can't be connected".to_owned(),
+    ))
+  }
+}
+
+#[cfg(test)]
+mod test {
+  use super::*;
+
+  #[test]
+  fn test_parse_fault_with_delay() {
+    assert_eq!(
+      "panic-task@10".parse::<InjectedFault>().unwrap(),
+      InjectedFault::new(FaultKind::PanicTask, 10)
+    );
+  }
+
+  #[test]
+  fn test_parse_fault_without_delay() {
+    assert_eq!(
+      "drop-client-connection".parse::<InjectedFault>().unwrap(),
+      InjectedFault::new(FaultKind::DropClientConnection, 0)
+    );
+  }
+
+  #[test]
+  fn test_parse_fault_bad_kind() {
+    assert!("panic-everything@10".parse::<InjectedFault>().is_err());
+    assert!("".parse::<InjectedFault>().is_err());
+  }
+
+  #[test]
+  fn test_parse_fault_bad_delay() {
+    assert!("panic-task@soon".parse::<InjectedFault>().is_err());
+    assert!("panic-task@-1".parse::<InjectedFault>().is_err());
+    assert!("panic-task@".parse::<InjectedFault>().is_err());
+  }
+
+  #[test]
+  fn test_fault_display_round_trip() {
+    let fault = InjectedFault::new(FaultKind::StallDeviceManager, 3);
+    assert_eq!(fault.to_string().parse::<InjectedFault>().unwrap(), fault);
+  }
+
+  #[tokio::test(start_paused = true)]
+  async fn test_server_faults_in_order() {
+    let mut injector = FaultInjector {
+      start: Instant::now(),
+      faults: vec![
+        InjectedFault::new(FaultKind::DropClientConnection, 10),
+        InjectedFault::new(FaultKind::PanicMainThread, 5),
+        InjectedFault::new(FaultKind::DropClientConnection, 20),
+      ],
+    };
+    let start = injector.start;
+    assert_eq!(
+      injector.next_server_fault().await,
+      FaultKind::PanicMainThread
+    );
+    assert_eq!(start.elapsed(), Duration::from_secs(5));
+    assert_eq!(
+      injector.next_server_fault().await,
+      FaultKind::DropClientConnection
+    );
+    assert_eq!(start.elapsed(), Duration::from_secs(10));
+    assert_eq!(
+      injector.next_server_fault().await,
+      FaultKind::DropClientConnection
+    );
+    assert_eq!(start.elapsed(), Duration::from_secs(20));
+    // Nothing left, so this never resolves.
+    assert!(
+      tokio::time::timeout(Duration::from_secs(3600), injector.next_server_fault())
+        .await
+        .is_err()
+    );
+  }
+
+  #[tokio::test(start_paused = true)]
+  async fn test_overdue_server_fault_fires_immediately() {
+    let mut injector = FaultInjector {
+      start: Instant::now(),
+      faults: vec![InjectedFault::new(FaultKind::DropClientConnection, 5)],
+    };
+    tokio::time::advance(Duration::from_secs(30)).await;
+    let before = Instant::now();
+    assert_eq!(
+      injector.next_server_fault().await,
+      FaultKind::DropClientConnection
+    );
+    assert_eq!(before.elapsed(), Duration::ZERO);
+  }
+
+  #[test]
+  fn test_device_manager_setup_takes_stalls() {
+    let mut injector = FaultInjector {
+      start: Instant::now(),
+      faults: vec![
+        InjectedFault::new(FaultKind::StallDeviceManager, 5),
+        InjectedFault::new(FaultKind::DropClientConnection, 10),
+        InjectedFault::new(FaultKind::StallDeviceManager, 1),
+      ],
+    };
+    assert!(injector.device_manager_setup().is_some());
+    assert_eq!(
+      injector.faults,
+      vec![InjectedFault::new(FaultKind::DropClientConnection, 10)]
+    );
+    assert!(injector.device_manager_setup().is_none());
+  }
+}
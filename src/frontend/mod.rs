@@ -1,6 +1,7 @@
 pub mod process_messages;
 use crate::error::IntifaceError;
 use crate::remote_server::ButtplugRemoteServerEvent;
+use crate::repeater::ButtplugRepeaterEvent;
 use async_trait::async_trait;
 use futures::{pin_mut, Stream, StreamExt};
 pub use process_messages::{EngineMessage, IntifaceMessage};
@@ -44,6 +45,12 @@ pub async fn frontend_external_event_loop(
               info!("Got external stop request");
               break;
             }
+            // Handled by the engine's device config reload and user config command loops.
+            IntifaceMessage::ReloadDeviceConfig{}
+            | IntifaceMessage::ListUserDeviceConfigs{}
+            | IntifaceMessage::AddUserDeviceConfig{..}
+            | IntifaceMessage::EditUserDeviceConfig{..}
+            | IntifaceMessage::RemoveUserDeviceConfig{..} => {}
           },
           Err(_) => {
             info!("Frontend sender dropped, assuming connection lost, breaking.");
@@ -109,6 +116,47 @@ pub async fn frontend_server_event_loop(
   info!("Exiting server event receiver loop");
 }
 
+pub async fn frontend_repeater_event_loop(
+  receiver: impl Stream<Item = ButtplugRepeaterEvent>,
+  frontend: Arc<dyn Frontend>,
+  connection_cancellation_token: CancellationToken,
+) {
+  pin_mut!(receiver);
+
+  loop {
+    select! {
+      maybe_event = receiver.next() => {
+        match maybe_event {
+          Some(event) => match event {
+            ButtplugRepeaterEvent::ConnectionOpened { client_address, upstream_url } => {
+              info!("Repeater connection opened: {} -> {}", client_address, upstream_url);
+              frontend
+                .send(EngineMessage::RepeaterConnectionOpened { client_address, upstream_url })
+                .await;
+            }
+            ButtplugRepeaterEvent::ConnectionClosed { client_address, upstream_url, client_bytes, client_messages, server_bytes, server_messages, duration_ms } => {
+              info!("Repeater connection closed: {} -> {} ({}ms)", client_address, upstream_url, duration_ms);
+              frontend
+                .send(EngineMessage::RepeaterConnectionClosed { client_address, upstream_url, client_bytes, client_messages, server_bytes, server_messages, duration_ms })
+                .await;
+            }
+          },
+          None => {
+            info!("Lost connection with repeater, breaking.");
+            break;
+          },
+        }
+      },
+      _ = connection_cancellation_token.cancelled() => {
+        info!("Connection cancellation token activated, breaking from frontend repeater event loop");
+        break;
+      }
+    }
+  }
+  info!("Exiting repeater event receiver loop");
+}
+
+#[allow(dead_code)]
 #[derive(Default)]
 struct NullFrontend {
   notify: Arc<Notify>,
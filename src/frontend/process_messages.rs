@@ -1,4 +1,10 @@
-use buttplug::server::device::configuration::UserDeviceIdentifier;
+use crate::{
+  device_config::DeviceConfigDiff,
+  error::IntifaceEngineError,
+  options::OptionsError,
+  user_config::{UserDeviceConfigEdit, UserDeviceConfigEntry},
+};
+use buttplug::server::device::configuration::{UserDeviceDefinition, UserDeviceIdentifier};
 use serde::{Deserialize, Serialize};
 
 // Everything in this struct is an object, even if it has null contents. This is to make other
@@ -10,7 +16,16 @@ pub enum EngineMessage {
   },
   EngineStarted {},
   EngineError {
-    error: String,
+    /// Stable, machine readable error code. See [IntifaceEngineError::code].
+    code: String,
+    message: String,
+    details: Vec<String>,
+  },
+  EngineWarning {
+    warning: String,
+  },
+  EngineOptionsInvalid {
+    errors: Vec<OptionsError>,
   },
   EngineServerCreated {},
   EngineStopped {},
@@ -31,10 +46,70 @@ pub enum EngineMessage {
   ClientRejected {
     reason: String,
   },
+  UserConfigSaveFailed {
+    error: String,
+  },
+  UserDeviceConfigs {
+    configs: Vec<UserDeviceConfigEntry>,
+  },
+  UserDeviceConfigUpdated {
+    identifier: UserDeviceIdentifier,
+    config: UserDeviceDefinition,
+  },
+  UserDeviceConfigRemoved {
+    identifier: UserDeviceIdentifier,
+  },
+  UserDeviceConfigError {
+    error: String,
+  },
+  DeviceConfigReloaded {
+    diff: DeviceConfigDiff,
+  },
+  DeviceConfigReloadFailed {
+    error: String,
+  },
+  RepeaterConnectionOpened {
+    client_address: String,
+    upstream_url: String,
+  },
+  RepeaterConnectionClosed {
+    client_address: String,
+    upstream_url: String,
+    client_bytes: u64,
+    client_messages: u64,
+    server_bytes: u64,
+    server_messages: u64,
+    duration_ms: u64,
+  },
+}
+
+impl From<&IntifaceEngineError> for EngineMessage {
+  fn from(error: &IntifaceEngineError) -> Self {
+    EngineMessage::EngineError {
+      code: error.code().to_owned(),
+      message: error.to_string(),
+      details: error.details(),
+    }
+  }
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub enum IntifaceMessage {
-  RequestEngineVersion { expected_version: u32 },
+  RequestEngineVersion {
+    expected_version: u32,
+  },
   Stop {},
+  ReloadDeviceConfig {},
+  ListUserDeviceConfigs {},
+  AddUserDeviceConfig {
+    identifier: UserDeviceIdentifier,
+    config: UserDeviceDefinition,
+  },
+  EditUserDeviceConfig {
+    identifier: UserDeviceIdentifier,
+    edit: UserDeviceConfigEdit,
+  },
+  RemoveUserDeviceConfig {
+    identifier: UserDeviceIdentifier,
+  },
 }
@@ -2,17 +2,30 @@
 extern crate tracing;
 mod backdoor_server;
 mod buttplug_server;
+mod device_config;
 mod engine;
 mod error;
+mod fault_injection;
 mod frontend;
 mod mdns;
 mod options;
 mod remote_server;
 mod repeater;
-pub use backdoor_server::BackdoorServer;
-pub use engine::IntifaceEngine;
+mod user_config;
+pub use backdoor_server::{
+  BackdoorRestartPolicy, BackdoorScope, BackdoorServer, BackdoorServerError, BackdoorSession,
+  DeniedMessage,
+};
+pub use device_config::DeviceConfigDiff;
+pub use engine::{IntifaceEngine, IntifaceEngineBuilder};
 pub use error::*;
+pub use fault_injection::{FaultKind, InjectedFault};
 pub use frontend::{EngineMessage, Frontend, IntifaceMessage};
-pub use options::{EngineOptions, EngineOptionsBuilder, EngineOptionsExternal};
+pub use mdns::{discover_engines, DiscoveredEngine};
+pub use options::{EngineOptions, EngineOptionsBuilder, EngineOptionsExternal, OptionsError};
 pub use remote_server::{ButtplugRemoteServer, ButtplugServerConnectorError};
-pub use repeater::ButtplugRepeater;
+pub use repeater::{replay_repeater_session, ButtplugRepeater, ButtplugRepeaterEvent};
+pub use user_config::{
+  export_user_config_bundle, import_user_config, import_user_config_file, UserConfigImportReport,
+  UserDeviceConfigEdit, UserDeviceConfigEntry, UserDeviceFeatureStepLimit,
+};
@@ -0,0 +1,376 @@
+// libmdns only handles advertising, so this is a small one-shot mDNS browser. We send a single PTR
+// query with the unicast-response bit set, then collect whatever answers come back to our socket
+// until the timeout runs out. This is enough for finding engines on a LAN, it is not a general
+// purpose DNS-SD implementation.
+
+use super::ENGINE_SERVICE_TYPE;
+use crate::IntifaceError;
+use getset::{CopyGetters, Getters};
+use serde::{Deserialize, Serialize};
+use std::{
+  collections::{HashMap, HashSet},
+  net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
+  time::Duration,
+};
+use tokio::{
+  net::UdpSocket,
+  time::{timeout_at, Instant},
+};
+
+const MDNS_GROUP: Ipv4Addr = Ipv4Addr::new(224, 0, 0, 251);
+const MDNS_PORT: u16 = 5353;
+
+const TYPE_A: u16 = 1;
+const TYPE_PTR: u16 = 12;
+const TYPE_TXT: u16 = 16;
+const TYPE_AAAA: u16 = 28;
+const TYPE_SRV: u16 = 33;
+const CLASS_IN: u16 = 1;
+// Top bit of the question class asks responders to reply directly to us instead of the group.
+const CLASS_UNICAST_RESPONSE: u16 = 0x8000;
+
+#[derive(Debug, Clone, Serialize, Deserialize, Getters, CopyGetters)]
+pub struct DiscoveredEngine {
+  #[getset(get = "pub")]
+  instance_name: String,
+  #[getset(get = "pub")]
+  hostname: String,
+  #[getset(get = "pub")]
+  addresses: Vec<IpAddr>,
+  #[getset(get_copy = "pub")]
+  port: u16,
+  #[getset(get = "pub")]
+  txt: HashMap<String, String>,
+}
+
+impl DiscoveredEngine {
+  /// Websocket URL for connecting to this engine, using the advertised scheme and the first
+  /// advertised address.
+  pub fn websocket_address(&self) -> Option<String> {
+    let scheme = self.txt.get("scheme").map(|s| s.as_str()).unwrap_or("ws");
+    let address = self.addresses.first()?;
+    Some(format!(
+      "{}://{}",
+      scheme,
+      SocketAddr::new(*address, self.port)
+    ))
+  }
+}
+
+#[derive(Default)]
+struct DiscoveryRecords {
+  instances: HashSet<String>,
+  services: HashMap<String, (u16, String)>,
+  txt: HashMap<String, HashMap<String, String>>,
+  addresses: HashMap<String, Vec<IpAddr>>,
+  sources: HashMap<String, IpAddr>,
+}
+
+fn build_query(service_type: &str) -> Vec<u8> {
+  // Header: id 0, standard query, one question.
+  let mut query = vec![0, 0, 0, 0, 0, 1, 0, 0, 0, 0, 0, 0];
+  for label in service_type.split('.').filter(|l| !l.is_empty()) {
+    query.push(label.len() as u8);
+    query.extend_from_slice(label.as_bytes());
+  }
+  query.push(0);
+  query.extend_from_slice(&TYPE_PTR.to_be_bytes());
+  query.extend_from_slice(&(CLASS_IN | CLASS_UNICAST_RESPONSE).to_be_bytes());
+  query
+}
+
+fn read_u16(packet: &[u8], offset: usize) -> Option<u16> {
+  Some(u16::from_be_bytes(
+    packet.get(offset..offset + 2)?.try_into().ok()?,
+  ))
+}
+
+// Reads a (possibly compressed) name, returning it along with the offset just past it.
+fn read_name(packet: &[u8], mut offset: usize) -> Option<(String, usize)> {
+  let mut labels = vec![];
+  let mut end = None;
+  // Bound the number of compression jumps so a malicious packet can't loop us forever.
+  for _ in 0..64 {
+    let len = *packet.get(offset)? as usize;
+    if len == 0 {
+      return Some((labels.join("."), end.unwrap_or(offset + 1)));
+    } else if len & 0xC0 == 0xC0 {
+      let pointer = (read_u16(packet, offset)? & 0x3FFF) as usize;
+      end.get_or_insert(offset + 2);
+      offset = pointer;
+    } else {
+      let label = packet.get(offset + 1..offset + 1 + len)?;
+      labels.push(String::from_utf8_lossy(label).into_owned());
+      offset += 1 + len;
+    }
+  }
+  None
+}
+
+fn parse_txt(rdata: &[u8]) -> HashMap<String, String> {
+  let mut records = HashMap::new();
+  let mut offset = 0;
+  while let Some(len) = rdata.get(offset) {
+    let Some(entry) = rdata.get(offset + 1..offset + 1 + *len as usize) else {
+      break;
+    };
+    let entry = String::from_utf8_lossy(entry);
+    if let Some((key, value)) = entry.split_once('=') {
+      records.insert(key.to_owned(), value.to_owned());
+    } else if !entry.is_empty() {
+      records.insert(entry.into_owned(), String::new());
+    }
+    offset += 1 + *len as usize;
+  }
+  records
+}
+
+fn parse_response(
+  packet: &[u8],
+  source: IpAddr,
+  service_type: &str,
+  records: &mut DiscoveryRecords,
+) -> Option<()> {
+  let question_count = read_u16(packet, 4)?;
+  let record_count =
+    read_u16(packet, 6)? as usize + read_u16(packet, 8)? as usize + read_u16(packet, 10)? as usize;
+  let mut offset = 12;
+  for _ in 0..question_count {
+    let (_, next) = read_name(packet, offset)?;
+    offset = next + 4;
+  }
+  for _ in 0..record_count {
+    let (name, next) = read_name(packet, offset)?;
+    let record_type = read_u16(packet, next)?;
+    let rdata_len = read_u16(packet, next + 8)? as usize;
+    let rdata_offset = next + 10;
+    let rdata = packet.get(rdata_offset..rdata_offset + rdata_len)?;
+    offset = rdata_offset + rdata_len;
+    match record_type {
+      TYPE_PTR if name.eq_ignore_ascii_case(service_type) => {
+        let (instance, _) = read_name(packet, rdata_offset)?;
+        records.sources.insert(instance.clone(), source);
+        records.instances.insert(instance);
+      }
+      TYPE_SRV => {
+        let port = read_u16(packet, rdata_offset + 4)?;
+        let (target, _) = read_name(packet, rdata_offset + 6)?;
+        records.services.insert(name, (port, target));
+      }
+      TYPE_TXT => {
+        records.txt.insert(name, parse_txt(rdata));
+      }
+      TYPE_A => {
+        let octets: [u8; 4] = rdata.try_into().ok()?;
+        records
+          .addresses
+          .entry(name)
+          .or_default()
+          .push(IpAddr::V4(Ipv4Addr::from(octets)));
+      }
+      TYPE_AAAA => {
+        let octets: [u8; 16] = rdata.try_into().ok()?;
+        records
+          .addresses
+          .entry(name)
+          .or_default()
+          .push(IpAddr::V6(Ipv6Addr::from(octets)));
+      }
+      _ => {}
+    }
+  }
+  Some(())
+}
+
+/// Browses the local network for Intiface Engines advertising themselves via mDNS, waiting up to
+/// `wait_time` for replies.
+pub async fn discover_engines(wait_time: Duration) -> Result<Vec<DiscoveredEngine>, IntifaceError> {
+  discover_service(ENGINE_SERVICE_TYPE, wait_time).await
+}
+
+async fn discover_service(
+  service_type: &str,
+  wait_time: Duration,
+) -> Result<Vec<DiscoveredEngine>, IntifaceError> {
+  let service_type = format!("{}.local", service_type);
+  let socket = UdpSocket::bind((Ipv4Addr::UNSPECIFIED, 0))
+    .await
+    .map_err(|e| IntifaceError::new(&format!("Cannot bind mDNS discovery socket: {:?}", e)))?;
+  socket
+    .send_to(&build_query(&service_type), (MDNS_GROUP, MDNS_PORT))
+    .await
+    .map_err(|e| IntifaceError::new(&format!("Cannot send mDNS discovery query: {:?}", e)))?;
+
+  let deadline = Instant::now() + wait_time;
+  let mut records = DiscoveryRecords::default();
+  let mut buf = vec![0u8; 9000];
+  while let Ok(result) = timeout_at(deadline, socket.recv_from(&mut buf)).await {
+    match result {
+      Ok((len, source)) => {
+        if parse_response(&buf[..len], source.ip(), &service_type, &mut records).is_none() {
+          debug!("Ignoring malformed mDNS response from {}", source);
+        }
+      }
+      Err(e) => {
+        warn!("Error receiving mDNS discovery response: {:?}", e);
+        break;
+      }
+    }
+  }
+
+  let suffix = format!(".{}", service_type);
+  let mut engines: Vec<DiscoveredEngine> = records
+    .instances
+    .iter()
+    .filter_map(|instance| {
+      let (port, hostname) = records.services.get(instance)?.clone();
+      let addresses = records
+        .addresses
+        .get(&hostname)
+        .cloned()
+        .or_else(|| records.sources.get(instance).map(|ip| vec![*ip]))
+        .unwrap_or_default();
+      Some(DiscoveredEngine {
+        instance_name: instance
+          .strip_suffix(&suffix)
+          .unwrap_or(instance)
+          .to_owned(),
+        hostname,
+        addresses,
+        port,
+        txt: records.txt.get(instance).cloned().unwrap_or_default(),
+      })
+    })
+    .collect();
+  engines.sort_by(|a, b| a.instance_name.cmp(&b.instance_name));
+  Ok(engines)
+}
+
+#[cfg(test)]
+mod test {
+  use super::*;
+
+  // A libmdns response to our query, as sent by an engine advertising with `--mdns-suffix test`.
+  // libmdns doesn't compress names.
+  const LIBMDNS_RESPONSE: &[&str] = &[
+    "000084000000000400000000105f696e7469666163655f656e67696e65045f746370056c6f63616c00000c00",
+    "010000003c002b0d496e7469666163652074657374105f696e7469666163655f656e67696e65045f74637005",
+    "6c6f63616c000d496e7469666163652074657374105f696e7469666163655f656e67696e65045f746370056c",
+    "6f63616c00002100010000003c00100000000030de02766d056c6f63616c000d496e74696661636520746573",
+    "74105f696e7469666163655f656e67696e65045f746370056c6f63616c00001000010000003c005106706174",
+    "683d2f09736368656d653d77730b6d6f64653d656e67696e65146e616d653d42757474706c75672053657276",
+    "65720d76657273696f6e3d332e302e38107374617475733d617661696c61626c6502766d056c6f63616c0000",
+    "0100010000003c0004c0000202",
+  ];
+
+  // The same kind of response with names compressed, the way Avahi and Bonjour send them.
+  const COMPRESSED_RESPONSE: &[&str] = &[
+    "000084000000000400000000105f696e7469666163655f656e67696e65045f746370056c6f63616c00000c00",
+    "0100000078001310496e746966616365204465736b746f70c00cc03300210001000000780010000000003039",
+    "076465736b746f70c022c0330010000100000078001609736368656d653d77730b6d6f64653d656e67696e65",
+    "c058000100010000007800040a000005",
+  ];
+
+  fn packet(hex: &[&str]) -> Vec<u8> {
+    let hex = hex.concat();
+    (0..hex.len())
+      .step_by(2)
+      .map(|i| u8::from_str_radix(&hex[i..i + 2], 16).unwrap())
+      .collect()
+  }
+
+  fn parse(packet: &[u8]) -> Option<DiscoveryRecords> {
+    let mut records = DiscoveryRecords::default();
+    let service_type = format!("{}.local", ENGINE_SERVICE_TYPE);
+    parse_response(
+      packet,
+      IpAddr::V4(Ipv4Addr::LOCALHOST),
+      &service_type,
+      &mut records,
+    )?;
+    Some(records)
+  }
+
+  #[test]
+  fn test_parse_libmdns_response() {
+    let records = parse(&packet(LIBMDNS_RESPONSE)).unwrap();
+    let instance = "Intiface test._intiface_engine._tcp.local";
+    assert!(records.instances.contains(instance));
+    assert_eq!(
+      records.services.get(instance),
+      Some(&(12510, "vm.local".to_owned()))
+    );
+    let txt = records.txt.get(instance).unwrap();
+    assert_eq!(txt.get("scheme").map(|s| s.as_str()), Some("ws"));
+    assert_eq!(txt.get("version").map(|s| s.as_str()), Some("3.0.8"));
+    assert_eq!(
+      records.addresses.get("vm.local"),
+      Some(&vec![IpAddr::V4(Ipv4Addr::new(192, 0, 2, 2))])
+    );
+  }
+
+  #[test]
+  fn test_parse_compressed_response() {
+    let records = parse(&packet(COMPRESSED_RESPONSE)).unwrap();
+    let instance = "Intiface Desktop._intiface_engine._tcp.local";
+    assert!(records.instances.contains(instance));
+    assert_eq!(
+      records.services.get(instance),
+      Some(&(12345, "desktop.local".to_owned()))
+    );
+    assert_eq!(
+      records.txt.get(instance).and_then(|txt| txt.get("mode")),
+      Some(&"engine".to_owned())
+    );
+    assert_eq!(
+      records.addresses.get("desktop.local"),
+      Some(&vec![IpAddr::V4(Ipv4Addr::new(10, 0, 0, 5))])
+    );
+  }
+
+  #[test]
+  fn test_read_name_follows_pointer() {
+    let packet = packet(COMPRESSED_RESPONSE);
+    // The SRV record's name is nothing but a pointer to the instance name in the PTR record.
+    assert_eq!(&packet[0x46..0x48], &[0xc0, 0x33]);
+    assert_eq!(
+      read_name(&packet, 0x46),
+      Some((
+        "Intiface Desktop._intiface_engine._tcp.local".to_owned(),
+        0x48
+      ))
+    );
+  }
+
+  #[test]
+  fn test_read_name_pointer_loop() {
+    // A pointer to itself.
+    let mut packet = vec![0; 12];
+    packet.extend_from_slice(&[0xc0, 0x0c]);
+    assert_eq!(read_name(&packet, 12), None);
+    // A label, then a pointer back to it.
+    let mut packet = vec![0; 12];
+    packet.extend_from_slice(&[0x03, b'f', b'o', b'o', 0xc0, 0x0c]);
+    assert_eq!(read_name(&packet, 12), None);
+    // And a bad response as a whole is rejected.
+    let mut response = packet[..12].to_vec();
+    response[7] = 1;
+    response.extend_from_slice(&[0xc0, 0x0c]);
+    assert!(parse(&response).is_none());
+  }
+
+  #[test]
+  fn test_truncated_rdata() {
+    let packet = packet(LIBMDNS_RESPONSE);
+    // Cut off in the middle of the A record's address.
+    assert!(parse(&packet[..packet.len() - 2]).is_none());
+    // Claim more SRV rdata than the packet holds.
+    let mut packet = self::packet(COMPRESSED_RESPONSE);
+    packet[0x51] = 0xff;
+    assert!(parse(&packet).is_none());
+    // TXT entries running past the end of the rdata are dropped.
+    let txt = parse_txt(b"\x09scheme=ws\x0bmode");
+    assert_eq!(txt.len(), 1);
+    assert_eq!(txt.get("scheme").map(|s| s.as_str()), Some("ws"));
+  }
+}
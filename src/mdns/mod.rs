@@ -0,0 +1,266 @@
+mod discovery;
+
+use crate::{
+  error::IntifaceError, options::EngineOptions, remote_server::ButtplugRemoteServerEvent,
+  ButtplugRepeaterEvent,
+};
+pub use discovery::{discover_engines, DiscoveredEngine};
+use futures::{pin_mut, Stream, StreamExt};
+use rand::distr::{Alphanumeric, SampleString};
+use std::net::IpAddr;
+use tokio::select;
+use tokio_util::sync::CancellationToken;
+
+const VERSION: &str = env!("CARGO_PKG_VERSION");
+const ENGINE_SERVICE_TYPE: &str = "_intiface_engine._tcp";
+const REPEATER_SERVICE_TYPE: &str = "_intiface_repeater._tcp";
+
+pub struct IntifaceMdns {
+  responder: libmdns::Responder,
+  svc: Option<libmdns::Service>,
+  service_type: String,
+  instance_name: String,
+  port: u16,
+  txt_records: Vec<String>,
+  // Advertised as the status TXT record, "connected" or "idle".
+  connected: bool,
+}
+
+// Only repeaters can serve TLS, the engine websocket server is always a plain websocket.
+fn websocket_scheme(options: &EngineOptions) -> &'static str {
+  if options.repeater_mode() && options.repeater_tls_cert_path().is_some() {
+    "wss"
+  } else {
+    "ws"
+  }
+}
+
+// Works out which addresses to advertise on, based on the interface names/addresses and IP version
+// options. An empty list means libmdns will use every interface it can find.
+fn allowed_ips(options: &EngineOptions) -> Result<Vec<IpAddr>, IntifaceError> {
+  if options.mdns_interfaces().is_empty() && !options.mdns_ipv4_only() && !options.mdns_ipv6_only()
+  {
+    return Ok(vec![]);
+  }
+  let interfaces = local_ip_address::list_afinet_netifas().map_err(|e| {
+    IntifaceError::new(&format!("Cannot list network interfaces for mDNS: {:?}", e))
+  })?;
+  let allowed_ips: Vec<IpAddr> = interfaces
+    .into_iter()
+    .filter(|(name, ip)| {
+      options.mdns_interfaces().is_empty()
+        || options
+          .mdns_interfaces()
+          .iter()
+          .any(|interface| interface == name || *interface == ip.to_string())
+    })
+    .map(|(_, ip)| ip)
+    .filter(|ip| !options.mdns_ipv4_only() || ip.is_ipv4())
+    .filter(|ip| !options.mdns_ipv6_only() || ip.is_ipv6())
+    .collect();
+  if allowed_ips.is_empty() {
+    Err(IntifaceError::new(
+      "No network interfaces match the mDNS interface and IP version options",
+    ))
+  } else {
+    Ok(allowed_ips)
+  }
+}
+
+impl IntifaceMdns {
+  fn new(
+    service_type: &str,
+    options: &EngineOptions,
+    port: u16,
+    txt_records: Vec<String>,
+  ) -> Result<Self, IntifaceError> {
+    let suffix = options
+      .mdns_suffix()
+      .clone()
+      .unwrap_or_else(|| Alphanumeric.sample_string(&mut rand::rng(), 6));
+    let instance_name = format!("Intiface {}", suffix);
+    info!(
+      "Bringing up mDNS Advertisment for {} using instance name {} on port {}",
+      service_type, instance_name, port
+    );
+    if !options.websocket_use_all_interfaces() {
+      warn!("mDNS advertisement is on, but the server is only listening on localhost. Other machines will not be able to connect.");
+    }
+
+    let (responder, task) =
+      libmdns::Responder::with_default_handle_and_ip_list(allowed_ips(options)?)
+        .map_err(|e| IntifaceError::new(&format!("Cannot start mDNS responder: {:?}", e)))?;
+    tokio::spawn(async move {
+      info!("Entering up mDNS task");
+      task.await;
+      info!("Exiting mDNS task");
+    });
+    let mut mdns = Self {
+      responder,
+      svc: None,
+      service_type: service_type.to_owned(),
+      instance_name,
+      port,
+      txt_records,
+      connected: false,
+    };
+    mdns.register();
+    Ok(mdns)
+  }
+
+  pub fn new_engine(options: &EngineOptions, port: u16) -> Result<Self, IntifaceError> {
+    let txt_records = vec![
+      "path=/".to_owned(),
+      format!("scheme={}", websocket_scheme(options)),
+      "mode=engine".to_owned(),
+      format!("name={}", options.server_name()),
+      format!("version={}", VERSION),
+    ];
+    Self::new(ENGINE_SERVICE_TYPE, options, port, txt_records)
+  }
+
+  pub fn new_repeater(options: &EngineOptions, port: u16) -> Result<Self, IntifaceError> {
+    let upstream = options
+      .repeater_remote_mdns_name()
+      .as_ref()
+      .or(options.repeater_remote_address().as_ref())
+      .cloned()
+      .unwrap_or_default();
+    let txt_records = vec![
+      "path=/".to_owned(),
+      format!("scheme={}", websocket_scheme(options)),
+      "mode=repeater".to_owned(),
+      format!("upstream={}", upstream),
+      format!(
+        "upstream_count={}",
+        1 + options.repeater_fan_out_addresses().len()
+      ),
+      format!("version={}", VERSION),
+    ];
+    Self::new(REPEATER_SERVICE_TYPE, options, port, txt_records)
+  }
+
+  fn register(&mut self) {
+    if self.svc.is_some() {
+      return;
+    }
+    let status = format!(
+      "status={}",
+      if self.connected { "connected" } else { "idle" }
+    );
+    let txt_records: Vec<&str> = self
+      .txt_records
+      .iter()
+      .map(|record| record.as_str())
+      .chain([status.as_str()])
+      .collect();
+    self.svc = Some(self.responder.register(
+      self.service_type.clone(),
+      self.instance_name.clone(),
+      self.port,
+      &txt_records,
+    ));
+  }
+
+  fn unregister(&mut self) {
+    // Dropping the service sends out a goodbye packet, so browsers remove us right away.
+    self.svc = None;
+  }
+
+  fn set_connected(&mut self, connected: bool) {
+    if self.connected == connected {
+      return;
+    }
+    self.connected = connected;
+    // libmdns can't update TXT records in place, so re-register to get the new status out.
+    if self.svc.is_some() {
+      self.unregister();
+      self.register();
+    }
+  }
+
+  /// Withdraws the advertisement while a client is connected to the server, and brings it back once
+  /// the client disconnects, so nobody tries to grab an engine that's already in use. Runs until
+  /// the event stream closes or the token is cancelled.
+  pub async fn track_server_events(
+    mut self,
+    receiver: impl Stream<Item = ButtplugRemoteServerEvent>,
+    cancellation_token: CancellationToken,
+  ) {
+    pin_mut!(receiver);
+    loop {
+      select! {
+        maybe_event = receiver.next() => match maybe_event {
+          Some(ButtplugRemoteServerEvent::ClientConnected(_)) => {
+            info!("Client connected, withdrawing mDNS advertisement.");
+            self.unregister();
+            self.connected = true;
+          }
+          Some(ButtplugRemoteServerEvent::ClientDisconnected) => {
+            info!("Client disconnected, restoring mDNS advertisement.");
+            self.connected = false;
+            self.register();
+          }
+          Some(_) => continue,
+          None => break,
+        },
+        _ = cancellation_token.cancelled() => break,
+      }
+    }
+    info!("Exiting mDNS server event loop");
+  }
+
+  /// Keeps the status TXT record up to date with whether the repeater has any clients. Repeaters can
+  /// take more than one client, so unlike engines, they stay advertised while in use. Runs until the
+  /// event stream closes or the token is cancelled.
+  pub async fn track_repeater_events(
+    mut self,
+    receiver: impl Stream<Item = ButtplugRepeaterEvent>,
+    cancellation_token: CancellationToken,
+  ) {
+    pin_mut!(receiver);
+    let mut open_connections = 0usize;
+    loop {
+      select! {
+        maybe_event = receiver.next() => match maybe_event {
+          Some(ButtplugRepeaterEvent::ConnectionOpened { .. }) => open_connections += 1,
+          Some(ButtplugRepeaterEvent::ConnectionClosed { .. }) => {
+            open_connections = open_connections.saturating_sub(1)
+          }
+          None => break,
+        },
+        _ = cancellation_token.cancelled() => break,
+      }
+      self.set_connected(open_connections > 0);
+    }
+    info!("Exiting mDNS repeater event loop");
+  }
+}
+
+#[cfg(test)]
+mod test {
+  use super::*;
+  use crate::EngineOptionsBuilder;
+
+  #[test]
+  fn test_websocket_scheme() {
+    assert_eq!(
+      websocket_scheme(
+        &EngineOptionsBuilder::default()
+          .websocket_port(12345)
+          .finish()
+      ),
+      "ws"
+    );
+    let mut repeater = EngineOptionsBuilder::default();
+    repeater
+      .use_repeater_mode()
+      .repeater_local_port(12345)
+      .repeater_remote_address("ws://192.0.2.1:12345");
+    assert_eq!(websocket_scheme(&repeater.finish()), "ws");
+    repeater
+      .repeater_tls_cert_path("cert.pem")
+      .repeater_tls_key_path("key.pem");
+    assert_eq!(websocket_scheme(&repeater.finish()), "wss");
+  }
+}
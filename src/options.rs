@@ -1,13 +1,61 @@
+use crate::{fault_injection::InjectedFault, IntifaceError};
 use getset::{CopyGetters, Getters};
+use serde::{Deserialize, Serialize};
+use std::{fs, path::Path};
+use thiserror::Error;
+
+// Matches the default in buttplug's websocket server device comm manager.
+const DEFAULT_DEVICE_WEBSOCKET_SERVER_PORT: u16 = 54817;
+
+// Variants are all objects, for the same reason as EngineMessage, since these get sent to
+// frontends.
+#[derive(Error, Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub enum OptionsError {
+  #[error(
+    "No websocket port or websocket client address set, engine has no way to talk to clients"
+  )]
+  NoServerTransport {},
+  #[error("Both a websocket port and a websocket client address are set, only one can be used")]
+  ConflictingServerTransport {},
+  #[error("Repeater mode requires a repeater port")]
+  MissingRepeaterLocalPort {},
+  #[error("Repeater mode requires a repeater remote address or repeater remote mDNS name")]
+  MissingRepeaterRemote {},
+  #[error(
+    "Both a repeater remote address and repeater remote mDNS name are set, only one can be used"
+  )]
+  ConflictingRepeaterRemote {},
+  #[error("Repeater TLS requires both a certificate and a private key")]
+  IncompleteRepeaterTls {},
+  #[error("Device websocket server port {port} is the same as the websocket port")]
+  DeviceWebsocketPortConflict { port: u16 },
+  #[error("Frontend websocket port {port} is already used by another server")]
+  FrontendWebsocketPortConflict { port: u16 },
+  #[error("mDNS cannot be restricted to both IPv4 only and IPv6 only")]
+  ConflictingMdnsIpVersions {},
+  #[error("The backdoor websocket requires a backdoor token")]
+  MissingBackdoorToken {},
+  #[error("Backdoor websocket port {port} is already used by another server")]
+  BackdoorWebsocketPortConflict { port: u16 },
+  #[error("The backdoor websocket is not available in repeater mode")]
+  BackdoorInRepeaterMode {},
+}
 
-#[derive(CopyGetters, Getters, Default, Debug, Clone)]
+#[derive(CopyGetters, Getters, Default, Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
 pub struct EngineOptions {
   #[getset(get = "pub")]
   device_config_json: Option<String>,
   #[getset(get = "pub")]
+  device_config_path: Option<String>,
+  #[getset(get = "pub")]
   user_device_config_json: Option<String>,
   #[getset(get = "pub")]
   user_device_config_path: Option<String>,
+  #[getset(get_copy = "pub")]
+  user_device_config_backup_count: Option<usize>,
+  #[getset(get_copy = "pub")]
+  watch_device_config: bool,
   #[getset(get = "pub")]
   server_name: String,
   #[getset(get_copy = "pub")]
@@ -21,6 +69,10 @@ pub struct EngineOptions {
   #[getset(get_copy = "pub")]
   frontend_in_process_channel: bool,
   #[getset(get_copy = "pub")]
+  backdoor_websocket_port: Option<u16>,
+  #[getset(get = "pub")]
+  backdoor_token: Option<String>,
+  #[getset(get_copy = "pub")]
   max_ping_time: u32,
   #[getset(get_copy = "pub")]
   allow_raw_messages: bool,
@@ -46,29 +98,55 @@ pub struct EngineOptions {
   crash_main_thread: bool,
   #[getset(get_copy = "pub")]
   crash_task_thread: bool,
+  #[getset(get = "pub")]
+  injected_faults: Vec<InjectedFault>,
   #[getset(get_copy = "pub")]
   broadcast_server_mdns: bool,
   #[getset(get = "pub")]
   mdns_suffix: Option<String>,
+  #[getset(get = "pub")]
+  mdns_interfaces: Vec<String>,
+  #[getset(get_copy = "pub")]
+  mdns_ipv4_only: bool,
+  #[getset(get_copy = "pub")]
+  mdns_ipv6_only: bool,
   #[getset(get_copy = "pub")]
   repeater_mode: bool,
   #[getset(get_copy = "pub")]
   repeater_local_port: Option<u16>,
   #[getset(get = "pub")]
   repeater_remote_address: Option<String>,
+  #[getset(get = "pub")]
+  repeater_remote_mdns_name: Option<String>,
+  #[getset(get = "pub")]
+  repeater_fan_out_addresses: Vec<String>,
+  #[getset(get = "pub")]
+  repeater_tls_cert_path: Option<String>,
+  #[getset(get = "pub")]
+  repeater_tls_key_path: Option<String>,
+  #[getset(get = "pub")]
+  repeater_record_directory: Option<String>,
+  #[getset(get = "pub")]
+  repeater_replay_path: Option<String>,
 }
 
-#[derive(Default, Debug, Clone)]
+#[derive(Default, Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
 pub struct EngineOptionsExternal {
   pub device_config_json: Option<String>,
+  pub device_config_path: Option<String>,
   pub user_device_config_json: Option<String>,
   pub user_device_config_path: Option<String>,
+  pub user_device_config_backup_count: Option<usize>,
+  pub watch_device_config: bool,
   pub server_name: String,
   pub websocket_use_all_interfaces: bool,
   pub websocket_port: Option<u16>,
   pub websocket_client_address: Option<String>,
   pub frontend_websocket_port: Option<u16>,
   pub frontend_in_process_channel: bool,
+  pub backdoor_websocket_port: Option<u16>,
+  pub backdoor_token: Option<String>,
   pub max_ping_time: u32,
   pub allow_raw_messages: bool,
   pub use_bluetooth_le: bool,
@@ -82,25 +160,40 @@ pub struct EngineOptionsExternal {
   pub device_websocket_server_port: Option<u16>,
   pub crash_main_thread: bool,
   pub crash_task_thread: bool,
+  pub injected_faults: Vec<InjectedFault>,
   pub broadcast_server_mdns: bool,
   pub mdns_suffix: Option<String>,
+  pub mdns_interfaces: Vec<String>,
+  pub mdns_ipv4_only: bool,
+  pub mdns_ipv6_only: bool,
   pub repeater_mode: bool,
   pub repeater_local_port: Option<u16>,
   pub repeater_remote_address: Option<String>,
+  pub repeater_remote_mdns_name: Option<String>,
+  pub repeater_fan_out_addresses: Vec<String>,
+  pub repeater_tls_cert_path: Option<String>,
+  pub repeater_tls_key_path: Option<String>,
+  pub repeater_record_directory: Option<String>,
+  pub repeater_replay_path: Option<String>,
 }
 
 impl From<EngineOptionsExternal> for EngineOptions {
   fn from(other: EngineOptionsExternal) -> Self {
     Self {
       device_config_json: other.device_config_json,
+      device_config_path: other.device_config_path,
       user_device_config_json: other.user_device_config_json,
       user_device_config_path: other.user_device_config_path,
+      user_device_config_backup_count: other.user_device_config_backup_count,
+      watch_device_config: other.watch_device_config,
       server_name: other.server_name,
       websocket_use_all_interfaces: other.websocket_use_all_interfaces,
       websocket_port: other.websocket_port,
       websocket_client_address: other.websocket_client_address,
       frontend_websocket_port: other.frontend_websocket_port,
       frontend_in_process_channel: other.frontend_in_process_channel,
+      backdoor_websocket_port: other.backdoor_websocket_port,
+      backdoor_token: other.backdoor_token,
       max_ping_time: other.max_ping_time,
       allow_raw_messages: other.allow_raw_messages,
       use_bluetooth_le: other.use_bluetooth_le,
@@ -114,11 +207,117 @@ impl From<EngineOptionsExternal> for EngineOptions {
       device_websocket_server_port: other.device_websocket_server_port,
       crash_main_thread: other.crash_main_thread,
       crash_task_thread: other.crash_task_thread,
+      injected_faults: other.injected_faults,
       broadcast_server_mdns: other.broadcast_server_mdns,
       mdns_suffix: other.mdns_suffix,
+      mdns_interfaces: other.mdns_interfaces,
+      mdns_ipv4_only: other.mdns_ipv4_only,
+      mdns_ipv6_only: other.mdns_ipv6_only,
       repeater_mode: other.repeater_mode,
       repeater_local_port: other.repeater_local_port,
       repeater_remote_address: other.repeater_remote_address,
+      repeater_remote_mdns_name: other.repeater_remote_mdns_name,
+      repeater_fan_out_addresses: other.repeater_fan_out_addresses,
+      repeater_tls_cert_path: other.repeater_tls_cert_path,
+      repeater_tls_key_path: other.repeater_tls_key_path,
+      repeater_record_directory: other.repeater_record_directory,
+      repeater_replay_path: other.repeater_replay_path,
+    }
+  }
+}
+
+impl EngineOptions {
+  /// Checks for missing or conflicting options, returning every problem found. The engine runs
+  /// this before starting anything, but it can also be called ahead of time to report errors.
+  pub fn validate(&self) -> Result<(), Vec<OptionsError>> {
+    let mut errors = vec![];
+    // Ports in use by the client facing server or repeater, for checking conflicts.
+    let mut server_ports = vec![];
+    if self.repeater_mode {
+      match self.repeater_local_port {
+        Some(port) => server_ports.push(port),
+        // Replays don't listen for clients, so they don't need a port.
+        None if self.repeater_replay_path.is_none() => {
+          errors.push(OptionsError::MissingRepeaterLocalPort {})
+        }
+        None => {}
+      }
+      match (
+        &self.repeater_remote_address,
+        &self.repeater_remote_mdns_name,
+      ) {
+        (None, None) => errors.push(OptionsError::MissingRepeaterRemote {}),
+        (Some(_), Some(_)) => errors.push(OptionsError::ConflictingRepeaterRemote {}),
+        _ => {}
+      }
+      if self.repeater_tls_cert_path.is_some() != self.repeater_tls_key_path.is_some() {
+        errors.push(OptionsError::IncompleteRepeaterTls {});
+      }
+    } else {
+      match (self.websocket_port, &self.websocket_client_address) {
+        (None, None) => errors.push(OptionsError::NoServerTransport {}),
+        (Some(_), Some(_)) => errors.push(OptionsError::ConflictingServerTransport {}),
+        _ => {}
+      }
+      if let Some(port) = self.websocket_port {
+        server_ports.push(port);
+      }
+      if self.use_device_websocket_server {
+        let port = self
+          .device_websocket_server_port
+          .unwrap_or(DEFAULT_DEVICE_WEBSOCKET_SERVER_PORT);
+        if self.websocket_port == Some(port) {
+          errors.push(OptionsError::DeviceWebsocketPortConflict { port });
+        }
+        server_ports.push(port);
+      }
+    }
+    if let Some(port) = self.frontend_websocket_port {
+      if server_ports.contains(&port) {
+        errors.push(OptionsError::FrontendWebsocketPortConflict { port });
+      }
+      server_ports.push(port);
+    }
+    if let Some(port) = self.backdoor_websocket_port {
+      if self.repeater_mode {
+        errors.push(OptionsError::BackdoorInRepeaterMode {});
+      }
+      if server_ports.contains(&port) {
+        errors.push(OptionsError::BackdoorWebsocketPortConflict { port });
+      }
+      if self
+        .backdoor_token
+        .as_ref()
+        .is_none_or(|token| token.is_empty())
+      {
+        errors.push(OptionsError::MissingBackdoorToken {});
+      }
+    }
+    if self.broadcast_server_mdns && self.mdns_ipv4_only && self.mdns_ipv6_only {
+      errors.push(OptionsError::ConflictingMdnsIpVersions {});
+    }
+    if errors.is_empty() {
+      Ok(())
+    } else {
+      Err(errors)
+    }
+  }
+
+  /// Loads options from a config file. Files ending in `.json` are read as JSON, anything else is
+  /// read as TOML. Keys use the same names as the option fields, and any missing keys are left at
+  /// their defaults.
+  pub fn from_config_file(path: &str) -> Result<Self, IntifaceError> {
+    let contents = fs::read_to_string(path)
+      .map_err(|e| IntifaceError::new(&format!("Error opening config file {}: {:?}", path, e)))?;
+    let is_json = Path::new(path)
+      .extension()
+      .is_some_and(|ext| ext.eq_ignore_ascii_case("json"));
+    if is_json {
+      serde_json::from_str(&contents)
+        .map_err(|e| IntifaceError::new(&format!("Error parsing JSON config file {}: {}", path, e)))
+    } else {
+      toml::from_str(&contents)
+        .map_err(|e| IntifaceError::new(&format!("Error parsing TOML config file {}: {}", path, e)))
     }
   }
 }
@@ -128,12 +327,24 @@ pub struct EngineOptionsBuilder {
   options: EngineOptions,
 }
 
+impl From<EngineOptions> for EngineOptionsBuilder {
+  fn from(options: EngineOptions) -> Self {
+    Self { options }
+  }
+}
+
 impl EngineOptionsBuilder {
   pub fn device_config_json(&mut self, value: &str) -> &mut Self {
     self.options.device_config_json = Some(value.to_owned());
     self
   }
 
+  /// Path the device config was loaded from, so it can be reloaded later.
+  pub fn device_config_path(&mut self, value: &str) -> &mut Self {
+    self.options.device_config_path = Some(value.to_owned());
+    self
+  }
+
   pub fn user_device_config_json(&mut self, value: &str) -> &mut Self {
     self.options.user_device_config_json = Some(value.to_owned());
     self
@@ -144,26 +355,39 @@ impl EngineOptionsBuilder {
     self
   }
 
+  /// Number of previous user device config versions to keep when saving (defaults to 3).
+  pub fn user_device_config_backup_count(&mut self, value: usize) -> &mut Self {
+    self.options.user_device_config_backup_count = Some(value);
+    self
+  }
+
+  /// If set, watch the device config paths and reload device configuration when they change. Base
+  /// device config changes are reported, but only take effect after a restart.
+  pub fn watch_device_config(&mut self, value: bool) -> &mut Self {
+    self.options.watch_device_config = value;
+    self
+  }
+
   pub fn server_name(&mut self, value: &str) -> &mut Self {
     self.options.server_name = value.to_owned();
     self
   }
 
-  #[cfg(debug_assertions)]
+  #[cfg(feature = "fault-injection")]
   pub fn crash_main_thread(&mut self, value: bool) -> &mut Self {
-    #[cfg(debug_assertions)]
-    {
-      self.options.crash_main_thread = value;
-    }
+    self.options.crash_main_thread = value;
     self
   }
 
-  #[cfg(debug_assertions)]
+  #[cfg(feature = "fault-injection")]
   pub fn crash_task_thread(&mut self, value: bool) -> &mut Self {
-    #[cfg(debug_assertions)]
-    {
-      self.options.crash_main_thread = value;
-    }
+    self.options.crash_task_thread = value;
+    self
+  }
+
+  #[cfg(feature = "fault-injection")]
+  pub fn inject_fault(&mut self, fault: InjectedFault) -> &mut Self {
+    self.options.injected_faults.push(fault);
     self
   }
 
@@ -237,6 +461,19 @@ impl EngineOptionsBuilder {
     self
   }
 
+  /// Port for local (localhost only) websocket access to the backdoor server. Requires a
+  /// [EngineOptionsBuilder::backdoor_token].
+  pub fn backdoor_websocket_port(&mut self, port: u16) -> &mut Self {
+    self.options.backdoor_websocket_port = Some(port);
+    self
+  }
+
+  /// Token backdoor websocket clients have to send as `Authorization: Bearer <token>`.
+  pub fn backdoor_token(&mut self, token: &str) -> &mut Self {
+    self.options.backdoor_token = Some(token.to_owned());
+    self
+  }
+
   pub fn device_websocket_server_port(&mut self, port: u16) -> &mut Self {
     self.options.device_websocket_server_port = Some(port);
     self
@@ -257,8 +494,27 @@ impl EngineOptionsBuilder {
     self
   }
 
+  pub fn mdns_interface(&mut self, interface: &str) -> &mut Self {
+    self.options.mdns_interfaces.push(interface.to_owned());
+    self
+  }
+
+  pub fn mdns_ipv4_only(&mut self, value: bool) -> &mut Self {
+    self.options.mdns_ipv4_only = value;
+    self
+  }
+
+  pub fn mdns_ipv6_only(&mut self, value: bool) -> &mut Self {
+    self.options.mdns_ipv6_only = value;
+    self
+  }
+
   pub fn use_repeater_mode(&mut self) -> &mut Self {
-    self.options.repeater_mode = true;
+    self.repeater_mode(true)
+  }
+
+  pub fn repeater_mode(&mut self, value: bool) -> &mut Self {
+    self.options.repeater_mode = value;
     self
   }
 
@@ -272,7 +528,242 @@ impl EngineOptionsBuilder {
     self
   }
 
+  pub fn repeater_remote_mdns_name(&mut self, name: &str) -> &mut Self {
+    self.options.repeater_remote_mdns_name = Some(name.to_owned());
+    self
+  }
+
+  pub fn repeater_fan_out_address(&mut self, addr: &str) -> &mut Self {
+    self
+      .options
+      .repeater_fan_out_addresses
+      .push(addr.to_owned());
+    self
+  }
+
+  pub fn repeater_tls_cert_path(&mut self, path: &str) -> &mut Self {
+    self.options.repeater_tls_cert_path = Some(path.to_owned());
+    self
+  }
+
+  pub fn repeater_tls_key_path(&mut self, path: &str) -> &mut Self {
+    self.options.repeater_tls_key_path = Some(path.to_owned());
+    self
+  }
+
+  pub fn repeater_record_directory(&mut self, path: &str) -> &mut Self {
+    self.options.repeater_record_directory = Some(path.to_owned());
+    self
+  }
+
+  pub fn repeater_replay_path(&mut self, path: &str) -> &mut Self {
+    self.options.repeater_replay_path = Some(path.to_owned());
+    self
+  }
+
   pub fn finish(&mut self) -> EngineOptions {
     self.options.clone()
   }
+
+  /// Like [EngineOptionsBuilder::finish], but fails if the options don't pass
+  /// [EngineOptions::validate].
+  pub fn try_finish(&mut self) -> Result<EngineOptions, Vec<OptionsError>> {
+    self.options.validate()?;
+    Ok(self.options.clone())
+  }
+}
+
+// The crash options can only be set with fault injection built in.
+#[cfg(test)]
+mod test {
+  use super::*;
+
+  fn engine_options() -> EngineOptionsBuilder {
+    let mut builder = EngineOptionsBuilder::default();
+    builder.websocket_port(12345);
+    builder
+  }
+
+  fn repeater_options() -> EngineOptionsBuilder {
+    let mut builder = EngineOptionsBuilder::default();
+    builder
+      .use_repeater_mode()
+      .repeater_local_port(12345)
+      .repeater_remote_address("ws://192.0.2.1:12345");
+    builder
+  }
+
+  #[test]
+  fn test_validate_valid_options() {
+    assert_eq!(engine_options().finish().validate(), Ok(()));
+    assert_eq!(repeater_options().finish().validate(), Ok(()));
+    assert_eq!(
+      EngineOptionsBuilder::default()
+        .websocket_client_address("ws://192.0.2.1:12345")
+        .finish()
+        .validate(),
+      Ok(())
+    );
+  }
+
+  #[test]
+  fn test_validate_server_transport() {
+    assert_eq!(
+      EngineOptionsBuilder::default().finish().validate(),
+      Err(vec![OptionsError::NoServerTransport {}])
+    );
+    assert_eq!(
+      engine_options()
+        .websocket_client_address("ws://192.0.2.1:12345")
+        .finish()
+        .validate(),
+      Err(vec![OptionsError::ConflictingServerTransport {}])
+    );
+  }
+
+  #[test]
+  fn test_validate_repeater_remote() {
+    let mut builder = EngineOptionsBuilder::default();
+    builder.use_repeater_mode().repeater_local_port(12345);
+    assert_eq!(
+      builder.finish().validate(),
+      Err(vec![OptionsError::MissingRepeaterRemote {}])
+    );
+    assert_eq!(
+      repeater_options()
+        .repeater_remote_mdns_name("Intiface Desktop")
+        .finish()
+        .validate(),
+      Err(vec![OptionsError::ConflictingRepeaterRemote {}])
+    );
+  }
+
+  #[test]
+  fn test_validate_repeater_local_port() {
+    let without_port = || {
+      let mut builder = EngineOptionsBuilder::default();
+      builder
+        .use_repeater_mode()
+        .repeater_remote_address("ws://192.0.2.1:12345");
+      builder
+    };
+    assert_eq!(
+      without_port().finish().validate(),
+      Err(vec![OptionsError::MissingRepeaterLocalPort {}])
+    );
+    // Replays don't listen for clients, so they don't need a port.
+    assert_eq!(
+      without_port()
+        .repeater_replay_path("session.jsonl")
+        .finish()
+        .validate(),
+      Ok(())
+    );
+  }
+
+  #[test]
+  fn test_validate_reports_every_error() {
+    assert_eq!(
+      EngineOptionsBuilder::default()
+        .use_repeater_mode()
+        .repeater_tls_cert_path("cert.pem")
+        .finish()
+        .validate(),
+      Err(vec![
+        OptionsError::MissingRepeaterLocalPort {},
+        OptionsError::MissingRepeaterRemote {},
+        OptionsError::IncompleteRepeaterTls {},
+      ])
+    );
+  }
+
+  #[test]
+  fn test_validate_port_conflicts() {
+    assert_eq!(
+      engine_options()
+        .use_device_websocket_server(true)
+        .device_websocket_server_port(12345)
+        .finish()
+        .validate(),
+      Err(vec![OptionsError::DeviceWebsocketPortConflict {
+        port: 12345
+      }])
+    );
+    assert_eq!(
+      engine_options()
+        .frontend_websocket_port(12345)
+        .finish()
+        .validate(),
+      Err(vec![OptionsError::FrontendWebsocketPortConflict {
+        port: 12345
+      }])
+    );
+    assert_eq!(
+      engine_options()
+        .backdoor_websocket_port(12345)
+        .backdoor_token("token")
+        .finish()
+        .validate(),
+      Err(vec![OptionsError::BackdoorWebsocketPortConflict {
+        port: 12345
+      }])
+    );
+  }
+
+  #[test]
+  fn test_validate_backdoor() {
+    assert_eq!(
+      engine_options()
+        .backdoor_websocket_port(12346)
+        .finish()
+        .validate(),
+      Err(vec![OptionsError::MissingBackdoorToken {}])
+    );
+    assert_eq!(
+      repeater_options()
+        .backdoor_websocket_port(12346)
+        .backdoor_token("token")
+        .finish()
+        .validate(),
+      Err(vec![OptionsError::BackdoorInRepeaterMode {}])
+    );
+  }
+
+  #[test]
+  fn test_validate_mdns_ip_versions() {
+    let both_ip_versions = || {
+      let mut builder = engine_options();
+      builder.mdns_ipv4_only(true).mdns_ipv6_only(true);
+      builder
+    };
+    // Only a problem if we're actually advertising.
+    assert_eq!(both_ip_versions().finish().validate(), Ok(()));
+    assert_eq!(
+      both_ip_versions()
+        .broadcast_server_mdns(true)
+        .finish()
+        .validate(),
+      Err(vec![OptionsError::ConflictingMdnsIpVersions {}])
+    );
+  }
+
+  #[cfg(feature = "fault-injection")]
+  #[test]
+  fn test_crash_task_thread_builder() {
+    let options = EngineOptionsBuilder::default()
+      .crash_task_thread(true)
+      .finish();
+    assert!(options.crash_task_thread());
+    assert!(!options.crash_main_thread());
+  }
+
+  #[cfg(feature = "fault-injection")]
+  #[test]
+  fn test_crash_main_thread_builder() {
+    let options = EngineOptionsBuilder::default()
+      .crash_main_thread(true)
+      .finish();
+    assert!(options.crash_main_thread());
+    assert!(!options.crash_task_thread());
+  }
 }
@@ -25,6 +25,7 @@ use serde::{Deserialize, Serialize};
 use std::sync::Arc;
 use thiserror::Error;
 use tokio::sync::{broadcast, mpsc, Notify};
+use tokio_util::sync::CancellationToken;
 
 // Clone derived here to satisfy tokio broadcast requirements.
 #[derive(Clone, Debug, Serialize, Deserialize)]
@@ -55,6 +56,7 @@ pub struct ButtplugRemoteServer {
   server: Arc<ButtplugServerDowngradeWrapper>,
   event_sender: broadcast::Sender<ButtplugRemoteServerEvent>,
   disconnect_notifier: Arc<Notify>,
+  device_event_token: CancellationToken,
 }
 
 async fn run_device_event_stream(
@@ -77,7 +79,7 @@ async fn run_device_event_stream(
                 let added_event = ButtplugRemoteServerEvent::DeviceAdded {
                   index: da.device_index(),
                   name: da.device_name().clone(),
-                  identifier: device_info.identifier().clone().into(),
+                  identifier: device_info.identifier().clone(),
                   display_name: device_info.display_name().clone(),
                 };
                 if remote_event_sender.send(added_event).is_err() {
@@ -137,11 +139,10 @@ async fn run_server<ConnectorType>(
             match server_clone.parse_message(client_message.clone()).await {
               Ok(ret_msg) => {
                 // Only send event if we just connected. Sucks to check it on every message but the boolean check should be quick.
-                if !connected && server_clone.connected() {
-                  if remote_event_sender_clone.receiver_count() > 0 {
-                    if remote_event_sender_clone.send(ButtplugRemoteServerEvent::ClientConnected(server_clone.client_name().unwrap_or("Buttplug Client (No name specified)".to_owned()).clone())).is_err() {
-                      error!("Cannot send event to owner, dropping and assuming local server thread has exited.");
-                    }
+                if !connected && server_clone.connected() && remote_event_sender_clone.receiver_count() > 0 {
+                  let client_name = server_clone.client_name().unwrap_or("Buttplug Client (No name specified)".to_owned());
+                  if remote_event_sender_clone.send(ButtplugRemoteServerEvent::ClientConnected(client_name)).is_err() {
+                    error!("Cannot send event to owner, dropping and assuming local server thread has exited.");
                   }
                 }
                 if connector_clone.send(ret_msg).await.is_err() {
@@ -149,7 +150,7 @@ async fn run_server<ConnectorType>(
                 }
               },
               Err(err_msg) => {
-                if connector_clone.send(err_msg.into()).await.is_err() {
+                if connector_clone.send(err_msg).await.is_err() {
                   error!("Cannot send reply to server, dropping and assuming remote server thread has exited.");
                 }
               }
@@ -171,7 +172,7 @@ async fn run_server<ConnectorType>(
             match &msg {
               ButtplugServerMessageV4::DeviceAdded(da) => {
                 if let Some(device_info) = server.device_manager().device_info(da.device_index()) {
-                  let added_event = ButtplugRemoteServerEvent::DeviceAdded { index: da.device_index(), name: da.device_name().clone(), identifier: device_info.identifier().clone().into(), display_name: device_info.display_name().clone() };
+                  let added_event = ButtplugRemoteServerEvent::DeviceAdded { index: da.device_index(), name: da.device_name().clone(), identifier: device_info.identifier().clone(), display_name: device_info.display_name().clone() };
                   if remote_event_sender.send(added_event).is_err() {
                     error!("Cannot send event to owner, dropping and assuming local server thread has exited.");
                   }
@@ -195,7 +196,7 @@ async fn run_server<ConnectorType>(
         }
         Some(msg) => {
           let connector_clone = shared_connector.clone();
-          if connector_clone.send(msg.into()).await.is_err() {
+          if connector_clone.send(msg).await.is_err() {
             error!("Server disappeared, exiting remote server thread.");
           }
         }
@@ -225,17 +226,25 @@ impl ButtplugRemoteServer {
     // Thanks to the existence of the backdoor server, device updates can happen for the lifetime to
     // the RemoteServer instance, not just during client connect. We need to make sure these are
     // emitted to the frontend.
+    //
+    // The server's event stream includes the device manager's, which can be shared and outlive us, so
+    // this needs stopping explicitly once we're dropped.
+    let device_event_token = CancellationToken::new();
     tokio::spawn({
       let server = wrapped_server.clone();
       let event_sender = event_sender.clone();
+      let device_event_token = device_event_token.clone();
       async move {
-        run_device_event_stream(server, event_sender).await;
+        device_event_token
+          .run_until_cancelled(run_device_event_stream(server, event_sender))
+          .await;
       }
     });
     Self {
       event_sender,
       server: wrapped_server.clone(),
       disconnect_notifier: Arc::new(Notify::new()),
+      device_event_token,
     }
   }
 
@@ -289,5 +298,6 @@ impl ButtplugRemoteServer {
 impl Drop for ButtplugRemoteServer {
   fn drop(&mut self) {
     self.disconnect_notifier.notify_waiters();
+    self.device_event_token.cancel();
   }
 }
@@ -0,0 +1,552 @@
+// Fan-out repeater support. One downstream client connection is mirrored to multiple upstream
+// Buttplug servers, which means we actually have to understand enough of the protocol to:
+//
+// - Merge device lists from all upstreams, remapping device indexes so they don't collide
+// - Route device commands to whichever upstream owns the device
+// - Collect the replies to broadcast messages (handshake, ping, scanning, etc...) so the client
+//   only ever sees one reply per message id
+//
+// Messages are handled as raw JSON values instead of typed buttplug messages, so this works
+// regardless of which message spec version the client and servers have agreed on.
+
+use super::{
+  session::{RepeaterFrameDirection, RepeaterSessionRecorder},
+  RepeaterConnectionStats,
+};
+use futures::{
+  stream::{self, select_all},
+  SinkExt, StreamExt,
+};
+use serde_json::{json, Map, Value};
+use std::collections::HashMap;
+use tokio::{
+  io::{AsyncRead, AsyncWrite},
+  net::TcpStream,
+  select,
+};
+use tokio_tungstenite::{tungstenite::Message, MaybeTlsStream, WebSocketStream};
+
+// Values of buttplug's ErrorCode enum, which serializes as a bare integer.
+const ERROR_CODE_MESSAGE: u8 = 3;
+const ERROR_CODE_DEVICE: u8 = 4;
+
+enum Destination {
+  Client,
+  Upstream(usize),
+}
+
+#[derive(PartialEq, Eq)]
+enum PendingKind {
+  DeviceList,
+  Single,
+}
+
+struct PendingRequest {
+  kind: PendingKind,
+  remaining: usize,
+  response: Option<Value>,
+  error: Option<Value>,
+  devices: Vec<Value>,
+}
+
+impl PendingRequest {
+  fn new(kind: PendingKind, remaining: usize) -> Self {
+    Self {
+      kind,
+      remaining,
+      response: None,
+      error: None,
+      devices: vec![],
+    }
+  }
+}
+
+fn error_message(id: u64, error_code: u8, error_message: &str) -> Value {
+  json!({
+    "Error": {
+      "Id": id,
+      "ErrorMessage": error_message,
+      "ErrorCode": error_code
+    }
+  })
+}
+
+// Every buttplug message is an object with a single key (the message type) mapped to the message
+// body.
+fn message_parts(msg: &Value) -> Option<(String, &Map<String, Value>)> {
+  let (msg_type, body) = msg.as_object()?.iter().next()?;
+  Some((msg_type.clone(), body.as_object()?))
+}
+
+fn message_body_mut(msg: &mut Value) -> Option<&mut Map<String, Value>> {
+  msg.as_object_mut()?.values_mut().next()?.as_object_mut()
+}
+
+struct FanOutRouter {
+  upstream_count: usize,
+  next_device_index: u32,
+  // Merged device index to (upstream, upstream device index)
+  devices: HashMap<u32, (usize, u32)>,
+  // (upstream, upstream device index) to merged device index
+  upstream_devices: HashMap<(usize, u32), u32>,
+  pending: HashMap<u64, PendingRequest>,
+  scanning_finished_count: usize,
+}
+
+impl FanOutRouter {
+  fn new(upstream_count: usize) -> Self {
+    Self {
+      upstream_count,
+      next_device_index: 0,
+      devices: HashMap::new(),
+      upstream_devices: HashMap::new(),
+      pending: HashMap::new(),
+      scanning_finished_count: 0,
+    }
+  }
+
+  fn merged_index(&mut self, upstream: usize, upstream_index: u32) -> u32 {
+    if let Some(index) = self.upstream_devices.get(&(upstream, upstream_index)) {
+      return *index;
+    }
+    let index = self.next_device_index;
+    self.next_device_index += 1;
+    self.devices.insert(index, (upstream, upstream_index));
+    self
+      .upstream_devices
+      .insert((upstream, upstream_index), index);
+    index
+  }
+
+  fn remap_device_index(
+    &mut self,
+    upstream: usize,
+    device: &mut Map<String, Value>,
+    allocate: bool,
+  ) {
+    let Some(upstream_index) = device.get("DeviceIndex").and_then(Value::as_u64) else {
+      return;
+    };
+    let upstream_index = upstream_index as u32;
+    let merged_index = if allocate {
+      Some(self.merged_index(upstream, upstream_index))
+    } else {
+      self
+        .upstream_devices
+        .get(&(upstream, upstream_index))
+        .copied()
+    };
+    if let Some(index) = merged_index {
+      device.insert("DeviceIndex".to_owned(), json!(index));
+    } else {
+      warn!(
+        "Upstream {} sent message for unknown device index {}",
+        upstream, upstream_index
+      );
+    }
+  }
+
+  fn route_client_message(&mut self, mut msg: Value) -> Vec<(Destination, Value)> {
+    let Some((msg_type, body)) = message_parts(&msg) else {
+      warn!("Cannot parse client message, dropping: {}", msg);
+      return vec![];
+    };
+    let id = body.get("Id").and_then(Value::as_u64).unwrap_or(0);
+    let device_index = body.get("DeviceIndex").and_then(Value::as_u64);
+
+    if msg_type == "StartScanning" {
+      self.scanning_finished_count = 0;
+    }
+
+    if let Some(device_index) = device_index {
+      let Some(&(upstream, upstream_index)) = self.devices.get(&(device_index as u32)) else {
+        return vec![(
+          Destination::Client,
+          error_message(
+            id,
+            ERROR_CODE_DEVICE,
+            &format!("Device index {} not found", device_index),
+          ),
+        )];
+      };
+      if let Some(body) = message_body_mut(&mut msg) {
+        body.insert("DeviceIndex".to_owned(), json!(upstream_index));
+      }
+      self
+        .pending
+        .insert(id, PendingRequest::new(PendingKind::Single, 1));
+      vec![(Destination::Upstream(upstream), msg)]
+    } else {
+      let kind = if msg_type == "RequestDeviceList" {
+        PendingKind::DeviceList
+      } else {
+        PendingKind::Single
+      };
+      self
+        .pending
+        .insert(id, PendingRequest::new(kind, self.upstream_count));
+      (0..self.upstream_count)
+        .map(|upstream| (Destination::Upstream(upstream), msg.clone()))
+        .collect()
+    }
+  }
+
+  fn route_upstream_message(&mut self, upstream: usize, mut msg: Value) -> Vec<Value> {
+    let Some((msg_type, body)) = message_parts(&msg) else {
+      warn!("Cannot parse upstream message, dropping: {}", msg);
+      return vec![];
+    };
+    let id = body.get("Id").and_then(Value::as_u64).unwrap_or(0);
+
+    match msg_type.as_str() {
+      "DeviceAdded" => {
+        if let Some(body) = message_body_mut(&mut msg) {
+          self.remap_device_index(upstream, body, true);
+        }
+      }
+      "DeviceRemoved" => {
+        let upstream_index = body.get("DeviceIndex").and_then(Value::as_u64);
+        let Some(index) =
+          upstream_index.and_then(|i| self.upstream_devices.remove(&(upstream, i as u32)))
+        else {
+          return vec![];
+        };
+        self.devices.remove(&index);
+        if let Some(body) = message_body_mut(&mut msg) {
+          body.insert("DeviceIndex".to_owned(), json!(index));
+        }
+      }
+      "DeviceList" => {
+        let mut devices = body
+          .get("Devices")
+          .and_then(Value::as_array)
+          .cloned()
+          .unwrap_or_default();
+        for device in devices.iter_mut().filter_map(Value::as_object_mut) {
+          self.remap_device_index(upstream, device, true);
+        }
+        if let Some(body) = message_body_mut(&mut msg) {
+          body.insert("Devices".to_owned(), Value::Array(devices));
+        }
+      }
+      "ScanningFinished" => {
+        // Only tell the client scanning is finished once all upstreams are done.
+        self.scanning_finished_count += 1;
+        if self.scanning_finished_count < self.upstream_count {
+          return vec![];
+        }
+      }
+      _ => {
+        if let Some(body) = message_body_mut(&mut msg) {
+          self.remap_device_index(upstream, body, false);
+        }
+      }
+    }
+
+    // Id 0 is reserved for server events, which go straight to the client.
+    if id == 0 {
+      return vec![msg];
+    }
+
+    let Some(pending) = self.pending.get_mut(&id) else {
+      warn!(
+        "Upstream {} replied to unknown message id {}, dropping.",
+        upstream, id
+      );
+      return vec![];
+    };
+    match msg_type.as_str() {
+      "Error" => {
+        pending.error.get_or_insert(msg);
+      }
+      "DeviceList" => {
+        if let Some(devices) = message_parts(&msg)
+          .and_then(|(_, body)| body.get("Devices"))
+          .and_then(Value::as_array)
+        {
+          pending.devices.extend(devices.iter().cloned());
+        }
+      }
+      _ => {
+        pending.response.get_or_insert(msg);
+      }
+    }
+    pending.remaining = pending.remaining.saturating_sub(1);
+    if pending.remaining > 0 {
+      return vec![];
+    }
+
+    let pending = self.pending.remove(&id).expect("Already checked existence");
+    if let Some(error) = pending.error {
+      vec![error]
+    } else if pending.kind == PendingKind::DeviceList {
+      vec![json!({
+        "DeviceList": {
+          "Id": id,
+          "Devices": pending.devices
+        }
+      })]
+    } else if let Some(response) = pending.response {
+      vec![response]
+    } else {
+      vec![error_message(
+        id,
+        ERROR_CODE_MESSAGE,
+        "No upstream server replied to message",
+      )]
+    }
+  }
+}
+
+fn parse_frame(text: &str) -> Vec<Value> {
+  match serde_json::from_str::<Value>(text) {
+    Ok(Value::Array(msgs)) => msgs,
+    Ok(_) | Err(_) => {
+      warn!(
+        "Cannot parse repeater frame as message array, dropping: {}",
+        text
+      );
+      vec![]
+    }
+  }
+}
+
+fn frame_message(msgs: Vec<Value>) -> Message {
+  Message::text(Value::Array(msgs).to_string())
+}
+
+pub(super) async fn run_fan_out_connection<S>(
+  client: WebSocketStream<S>,
+  upstreams: Vec<WebSocketStream<MaybeTlsStream<TcpStream>>>,
+  stats: &RepeaterConnectionStats,
+  recorder: Option<&RepeaterSessionRecorder>,
+) where
+  S: AsyncRead + AsyncWrite + Unpin,
+{
+  let mut router = FanOutRouter::new(upstreams.len());
+  let (mut client_write, mut client_read) = client.split();
+  let mut upstream_writes = vec![];
+  let mut upstream_reads = vec![];
+  for (upstream, ws_stream) in upstreams.into_iter().enumerate() {
+    let (write, read) = ws_stream.split();
+    upstream_writes.push(write);
+    // Tack a None on the end of each upstream stream, so we know when any of them closes.
+    upstream_reads.push(
+      read
+        .map(move |msg| (upstream, Some(msg)))
+        .chain(stream::once(async move { (upstream, None) }))
+        .boxed(),
+    );
+  }
+  let mut upstream_reads = select_all(upstream_reads);
+
+  loop {
+    select! {
+      client_msg = client_read.next() => match client_msg {
+        Some(Ok(msg @ Message::Text(_))) => {
+          stats.client_message(msg.len());
+          if let Some(recorder) = recorder {
+            recorder.record(RepeaterFrameDirection::ClientToServer, &msg);
+          }
+          let text = msg.into_text().expect("Already matched as text");
+          let mut client_msgs = vec![];
+          let mut upstream_msgs = vec![vec![]; upstream_writes.len()];
+          for msg in parse_frame(text.as_str()) {
+            for (destination, msg) in router.route_client_message(msg) {
+              match destination {
+                Destination::Client => client_msgs.push(msg),
+                Destination::Upstream(upstream) => upstream_msgs[upstream].push(msg),
+              }
+            }
+          }
+          if !client_msgs.is_empty() {
+            let client_frame = frame_message(client_msgs);
+            if let Some(recorder) = recorder {
+              recorder.record(RepeaterFrameDirection::ServerToClient, &client_frame);
+            }
+            if client_write.send(client_frame).await.is_err() {
+              info!("Client disconnected from fan-out repeater.");
+              break;
+            }
+          }
+          for (upstream, msgs) in upstream_msgs.into_iter().enumerate() {
+            if !msgs.is_empty() && upstream_writes[upstream].send(frame_message(msgs)).await.is_err() {
+              error!("Cannot send message to upstream {}, closing fan-out connection.", upstream);
+              return;
+            }
+          }
+        }
+        Some(Ok(Message::Close(_))) | None => {
+          info!("Client disconnected from fan-out repeater.");
+          break;
+        }
+        Some(Ok(_)) => continue,
+        Some(Err(e)) => {
+          error!("Error reading from fan-out repeater client: {:?}", e);
+          break;
+        }
+      },
+      upstream_msg = upstream_reads.next() => match upstream_msg {
+        Some((upstream, Some(Ok(Message::Text(text))))) => {
+          stats.server_message(text.len());
+          let client_msgs: Vec<Value> = parse_frame(text.as_str())
+            .into_iter()
+            .flat_map(|msg| router.route_upstream_message(upstream, msg))
+            .collect();
+          if !client_msgs.is_empty() {
+            let client_frame = frame_message(client_msgs);
+            if let Some(recorder) = recorder {
+              recorder.record(RepeaterFrameDirection::ServerToClient, &client_frame);
+            }
+            if client_write.send(client_frame).await.is_err() {
+              info!("Client disconnected from fan-out repeater.");
+              break;
+            }
+          }
+        }
+        Some((upstream, Some(Ok(Message::Close(_))))) | Some((upstream, None)) => {
+          info!("Upstream {} disconnected, closing fan-out connection.", upstream);
+          break;
+        }
+        Some((_, Some(Ok(_)))) => continue,
+        Some((upstream, Some(Err(e)))) => {
+          error!("Error reading from upstream {}: {:?}", upstream, e);
+          break;
+        }
+        None => break,
+      }
+    }
+  }
+  for mut upstream_write in upstream_writes {
+    let _ = upstream_write.close().await;
+  }
+  let _ = client_write.close().await;
+}
+
+#[cfg(test)]
+mod test {
+  use super::*;
+
+  fn device_added(index: u32, name: &str) -> Value {
+    json!({ "DeviceAdded": { "Id": 0, "DeviceIndex": index, "DeviceName": name } })
+  }
+
+  fn device_index(msg: &Value) -> Option<u64> {
+    message_parts(msg)?.1.get("DeviceIndex")?.as_u64()
+  }
+
+  // Two upstreams, each with a device at upstream index 0, merged as 0 and 1.
+  fn router_with_devices() -> FanOutRouter {
+    let mut router = FanOutRouter::new(2);
+    router.route_upstream_message(0, device_added(0, "Upstream 0 Device"));
+    router.route_upstream_message(1, device_added(0, "Upstream 1 Device"));
+    router
+  }
+
+  #[test]
+  fn test_device_indexes_remapped_across_upstreams() {
+    let mut router = FanOutRouter::new(2);
+    let added = router.route_upstream_message(0, device_added(0, "A"));
+    assert_eq!(device_index(&added[0]), Some(0));
+    let added = router.route_upstream_message(1, device_added(0, "B"));
+    assert_eq!(device_index(&added[0]), Some(1));
+    let added = router.route_upstream_message(0, device_added(5, "C"));
+    assert_eq!(device_index(&added[0]), Some(2));
+    // Seeing the same upstream device again keeps its index.
+    let added = router.route_upstream_message(1, device_added(0, "B"));
+    assert_eq!(device_index(&added[0]), Some(1));
+  }
+
+  #[test]
+  fn test_device_removed_cleans_up_mapping() {
+    let mut router = router_with_devices();
+    let removed =
+      router.route_upstream_message(1, json!({ "DeviceRemoved": { "Id": 0, "DeviceIndex": 0 } }));
+    assert_eq!(removed.len(), 1);
+    assert_eq!(device_index(&removed[0]), Some(1));
+    assert!(!router.devices.contains_key(&1));
+    assert!(!router.upstream_devices.contains_key(&(1, 0)));
+    // Removing it again, or something we never saw, isn't passed on.
+    let removed =
+      router.route_upstream_message(1, json!({ "DeviceRemoved": { "Id": 0, "DeviceIndex": 0 } }));
+    assert!(removed.is_empty());
+    // The other upstream's device is untouched.
+    assert_eq!(router.devices.get(&0), Some(&(0, 0)));
+  }
+
+  #[test]
+  fn test_device_command_routed_to_owning_upstream() {
+    let mut router = router_with_devices();
+    let routed =
+      router.route_client_message(json!({ "StopDeviceCmd": { "Id": 5, "DeviceIndex": 1 } }));
+    assert_eq!(routed.len(), 1);
+    let (destination, msg) = &routed[0];
+    assert!(matches!(destination, Destination::Upstream(1)));
+    assert_eq!(device_index(msg), Some(0));
+
+    let reply = router.route_upstream_message(1, json!({ "Ok": { "Id": 5 } }));
+    assert_eq!(reply, vec![json!({ "Ok": { "Id": 5 } })]);
+    assert!(router.pending.is_empty());
+  }
+
+  #[test]
+  fn test_device_list_merged_from_all_upstreams() {
+    let mut router = FanOutRouter::new(2);
+    let routed = router.route_client_message(json!({ "RequestDeviceList": { "Id": 3 } }));
+    assert_eq!(routed.len(), 2);
+    assert!(matches!(routed[0].0, Destination::Upstream(0)));
+    assert!(matches!(routed[1].0, Destination::Upstream(1)));
+
+    let reply = router.route_upstream_message(
+      0,
+      json!({ "DeviceList": { "Id": 3, "Devices": [{ "DeviceIndex": 0, "DeviceName": "A" }] } }),
+    );
+    assert!(reply.is_empty());
+    let reply = router.route_upstream_message(
+      1,
+      json!({ "DeviceList": { "Id": 3, "Devices": [{ "DeviceIndex": 2, "DeviceName": "B" }] } }),
+    );
+    assert_eq!(
+      reply,
+      vec![json!({ "DeviceList": { "Id": 3, "Devices": [
+        { "DeviceIndex": 0, "DeviceName": "A" },
+        { "DeviceIndex": 1, "DeviceName": "B" }
+      ] } })]
+    );
+    assert_eq!(router.devices.get(&1), Some(&(1, 2)));
+  }
+
+  #[test]
+  fn test_scanning_finished_waits_for_all_upstreams() {
+    let mut router = FanOutRouter::new(2);
+    router.route_client_message(json!({ "StartScanning": { "Id": 1 } }));
+    let finished = json!({ "ScanningFinished": { "Id": 0 } });
+    assert!(router
+      .route_upstream_message(0, finished.clone())
+      .is_empty());
+    assert_eq!(
+      router.route_upstream_message(1, finished.clone()),
+      vec![finished.clone()]
+    );
+    // A new scan starts the count over.
+    router.route_client_message(json!({ "StartScanning": { "Id": 2 } }));
+    assert!(router
+      .route_upstream_message(1, finished.clone())
+      .is_empty());
+  }
+
+  #[test]
+  fn test_unknown_device_index_replies_with_error() {
+    let mut router = router_with_devices();
+    let routed =
+      router.route_client_message(json!({ "StopDeviceCmd": { "Id": 7, "DeviceIndex": 9 } }));
+    assert_eq!(routed.len(), 1);
+    let (destination, msg) = &routed[0];
+    assert!(matches!(destination, Destination::Client));
+    assert_eq!(
+      msg,
+      &error_message(7, ERROR_CODE_DEVICE, "Device index 9 not found")
+    );
+    assert!(router.pending.is_empty());
+  }
+}
@@ -0,0 +1,342 @@
+// Is this just two examples from tokio_tungstenite glued together?
+//
+// It absolute is!
+//
+// Well, mostly. Fan-out mode (one client mirrored to multiple upstream servers) needs to actually
+// understand the protocol, so that lives in its own module.
+
+mod fan_out;
+mod session;
+mod tls;
+
+pub use session::replay_repeater_session;
+
+use crate::IntifaceError;
+use buttplug::util::stream::convert_broadcast_receiver_to_stream;
+use futures::Stream;
+use futures_util::{future, StreamExt, TryStreamExt};
+use log::info;
+use serde::{Deserialize, Serialize};
+use session::{RepeaterFrameDirection, RepeaterSessionRecorder};
+use std::{net::SocketAddr, path::PathBuf};
+use std::{
+  sync::atomic::{AtomicU64, Ordering},
+  time::Instant,
+};
+use tokio::{
+  io::{AsyncRead, AsyncWrite},
+  net::{TcpListener, TcpStream},
+  select,
+  sync::broadcast,
+};
+use tokio_rustls::TlsAcceptor;
+use tokio_tungstenite::{connect_async, MaybeTlsStream, WebSocketStream};
+use tokio_util::sync::CancellationToken;
+
+// Clone derived here to satisfy tokio broadcast requirements.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub enum ButtplugRepeaterEvent {
+  ConnectionOpened {
+    client_address: String,
+    upstream_url: String,
+  },
+  ConnectionClosed {
+    client_address: String,
+    upstream_url: String,
+    client_bytes: u64,
+    client_messages: u64,
+    server_bytes: u64,
+    server_messages: u64,
+    duration_ms: u64,
+  },
+}
+
+#[derive(Default)]
+struct RepeaterConnectionStats {
+  client_bytes: AtomicU64,
+  client_messages: AtomicU64,
+  server_bytes: AtomicU64,
+  server_messages: AtomicU64,
+}
+
+impl RepeaterConnectionStats {
+  fn client_message(&self, len: usize) {
+    self.client_bytes.fetch_add(len as u64, Ordering::Relaxed);
+    self.client_messages.fetch_add(1, Ordering::Relaxed);
+  }
+
+  fn server_message(&self, len: usize) {
+    self.server_bytes.fetch_add(len as u64, Ordering::Relaxed);
+    self.server_messages.fetch_add(1, Ordering::Relaxed);
+  }
+}
+
+pub struct ButtplugRepeater {
+  local_port: u16,
+  remote_addresses: Vec<String>,
+  stop_token: CancellationToken,
+  event_sender: broadcast::Sender<ButtplugRepeaterEvent>,
+  tls_acceptor: Option<TlsAcceptor>,
+  record_directory: Option<PathBuf>,
+  listen_on_all_interfaces: bool,
+}
+
+// Only assume ws:// if we weren't given a scheme, so wss:// upstreams work.
+fn normalize_remote_address(address: &str) -> String {
+  if address.contains("://") {
+    address.to_owned()
+  } else {
+    format!("ws://{}", address)
+  }
+}
+
+impl ButtplugRepeater {
+  pub fn new(local_port: u16, remote_address: &str, stop_token: CancellationToken) -> Self {
+    Self::new_fan_out(local_port, &[remote_address.to_owned()], stop_token)
+  }
+
+  /// Creates a repeater that mirrors each client connection to all of the given upstream servers.
+  /// Device lists from all upstreams are merged (with device indexes remapped), and device commands
+  /// are routed to whichever upstream owns the device. With a single address, this acts exactly
+  /// like [ButtplugRepeater::new].
+  pub fn new_fan_out(
+    local_port: u16,
+    remote_addresses: &[String],
+    stop_token: CancellationToken,
+  ) -> Self {
+    let (event_sender, _) = broadcast::channel(256);
+    Self {
+      local_port,
+      remote_addresses: remote_addresses.to_vec(),
+      stop_token,
+      event_sender,
+      tls_acceptor: None,
+      record_directory: None,
+      listen_on_all_interfaces: false,
+    }
+  }
+
+  /// If set, listen for clients on all interfaces. Otherwise, only listen on 127.0.0.1.
+  pub fn listen_on_all_interfaces(&mut self, value: bool) {
+    self.listen_on_all_interfaces = value;
+  }
+
+  /// Records every frame of every connection, in both directions, to a timestamped JSONL file in
+  /// the given directory. Recorded sessions can be played back with [replay_repeater_session].
+  pub fn record_sessions(&mut self, directory: &str) {
+    self.record_directory = Some(PathBuf::from(directory));
+  }
+
+  /// Terminates TLS for downstream clients, using the PEM encoded certificate chain and private key
+  /// at the given paths. Clients will then need to connect via wss://.
+  pub fn use_tls(&mut self, cert_path: &str, key_path: &str) -> Result<(), IntifaceError> {
+    self.tls_acceptor = Some(tls::load_tls_acceptor(cert_path, key_path)?);
+    Ok(())
+  }
+
+  pub fn event_stream(&self) -> impl Stream<Item = ButtplugRepeaterEvent> {
+    convert_broadcast_receiver_to_stream(self.event_sender.subscribe())
+  }
+
+  /// Accepts client connections until the stop token is cancelled. Fails if the local port can't be
+  /// bound.
+  pub async fn listen(&self) -> Result<(), IntifaceError> {
+    info!("Repeater loop starting");
+    let addr = if self.listen_on_all_interfaces {
+      format!("0.0.0.0:{}", self.local_port)
+    } else {
+      format!("127.0.0.1:{}", self.local_port)
+    };
+
+    let listener = TcpListener::bind(&addr)
+      .await
+      .map_err(|e| IntifaceError::new(&format!("Cannot bind repeater to {}: {:?}", addr, e)))?;
+    info!("Listening on: {}", addr);
+
+    loop {
+      select! {
+        stream_result = listener.accept() => {
+          match stream_result {
+            Ok((stream, client_addr)) => {
+              let remote_addresses = self
+                .remote_addresses
+                .iter()
+                .map(|address| normalize_remote_address(address))
+                .collect();
+              let record_directory = self.record_directory.clone();
+              let tls_acceptor = self.tls_acceptor.clone();
+              let event_sender = self.event_sender.clone();
+              tokio::spawn(async move {
+                if let Some(tls_acceptor) = tls_acceptor {
+                  match tls_acceptor.accept(stream).await {
+                    Ok(tls_stream) => {
+                      ButtplugRepeater::accept_connection(
+                        remote_addresses,
+                        tls_stream,
+                        client_addr,
+                        event_sender,
+                        record_directory,
+                      )
+                      .await
+                    }
+                    Err(e) => error!("TLS handshake with repeater client {} failed: {:?}", client_addr, e),
+                  }
+                } else {
+                  ButtplugRepeater::accept_connection(
+                    remote_addresses,
+                    stream,
+                    client_addr,
+                    event_sender,
+                    record_directory,
+                  )
+                  .await
+                }
+              });
+            },
+            Err(e) => {
+              error!("Error accepting new websocket for repeater: {:?}", e);
+              break;
+            }
+          }
+        },
+        _ = self.stop_token.cancelled() => {
+          info!("Repeater loop requested to stop, breaking.");
+          break;
+        }
+      }
+    }
+    info!("Repeater loop exiting");
+    Ok(())
+  }
+
+  async fn connect_upstream(
+    server_addr: &str,
+  ) -> Option<WebSocketStream<MaybeTlsStream<TcpStream>>> {
+    info!("Connecting to server {}", server_addr);
+
+    let server_url = match url::Url::parse(server_addr) {
+      Ok(url) => url,
+      Err(e) => {
+        error!("Cannot parse server address {}: {:?}", server_addr, e);
+        return None;
+      }
+    };
+
+    match connect_async(&server_url).await {
+      Ok((stream, _)) => {
+        info!("WebSocket handshake has been successfully completed");
+        Some(stream)
+      }
+      Err(e) => {
+        error!("Cannot connect: {:?}", e);
+        None
+      }
+    }
+  }
+
+  async fn accept_connection<S>(
+    server_addrs: Vec<String>,
+    stream: S,
+    client_addr: SocketAddr,
+    event_sender: broadcast::Sender<ButtplugRepeaterEvent>,
+    record_directory: Option<PathBuf>,
+  ) where
+    S: AsyncRead + AsyncWrite + Unpin,
+  {
+    info!("Client address: {}", client_addr);
+
+    // Anything can connect to us, including port scanners and plain HTTP clients, so a failed
+    // handshake just drops the connection.
+    let client_ws_stream = match tokio_tungstenite::accept_async(stream).await {
+      Ok(ws_stream) => ws_stream,
+      Err(e) => {
+        warn!(
+          "Websocket handshake with repeater client {} failed: {:?}",
+          client_addr, e
+        );
+        return;
+      }
+    };
+
+    info!("New WebSocket connection: {}", client_addr);
+
+    let mut upstreams = vec![];
+    for server_addr in &server_addrs {
+      match ButtplugRepeater::connect_upstream(server_addr).await {
+        Some(ws_stream) => upstreams.push(ws_stream),
+        None => return,
+      }
+    }
+    let upstream_url = server_addrs.join(", ");
+
+    let connection_start = Instant::now();
+    if event_sender.receiver_count() > 0
+      && event_sender
+        .send(ButtplugRepeaterEvent::ConnectionOpened {
+          client_address: client_addr.to_string(),
+          upstream_url: upstream_url.clone(),
+        })
+        .is_err()
+    {
+      warn!("Cannot update owner about repeater connection opening");
+    }
+
+    let recorder = if let Some(directory) = record_directory {
+      match RepeaterSessionRecorder::new(&directory, &client_addr).await {
+        Ok(recorder) => Some(recorder),
+        Err(e) => {
+          error!("Cannot start repeater session recording: {:?}", e);
+          None
+        }
+      }
+    } else {
+      None
+    };
+
+    let stats = RepeaterConnectionStats::default();
+    if upstreams.len() == 1 {
+      let ws_stream = upstreams.pop().expect("Already checked length");
+      let (server_write, server_read) = ws_stream.split();
+
+      let (client_write, client_read) = client_ws_stream.split();
+
+      let client_fut = client_read
+        .try_filter(|msg| future::ready(msg.is_text() || msg.is_binary()))
+        .inspect_ok(|msg| {
+          stats.client_message(msg.len());
+          if let Some(recorder) = &recorder {
+            recorder.record(RepeaterFrameDirection::ClientToServer, msg);
+          }
+        })
+        .forward(server_write);
+      let server_fut = server_read
+        .try_filter(|msg| future::ready(msg.is_text() || msg.is_binary()))
+        .inspect_ok(|msg| {
+          stats.server_message(msg.len());
+          if let Some(recorder) = &recorder {
+            recorder.record(RepeaterFrameDirection::ServerToClient, msg);
+          }
+        })
+        .forward(client_write);
+      future::select(client_fut, server_fut).await;
+    } else {
+      fan_out::run_fan_out_connection(client_ws_stream, upstreams, &stats, recorder.as_ref()).await;
+    }
+    info!("Closing repeater connection.");
+    if event_sender.receiver_count() > 0
+      && event_sender
+        .send(ButtplugRepeaterEvent::ConnectionClosed {
+          client_address: client_addr.to_string(),
+          upstream_url,
+          client_bytes: stats.client_bytes.load(Ordering::Relaxed),
+          client_messages: stats.client_messages.load(Ordering::Relaxed),
+          server_bytes: stats.server_bytes.load(Ordering::Relaxed),
+          server_messages: stats.server_messages.load(Ordering::Relaxed),
+          duration_ms: connection_start.elapsed().as_millis() as u64,
+        })
+        .is_err()
+    {
+      warn!("Cannot update owner about repeater connection closing");
+    }
+  }
+}
@@ -0,0 +1,162 @@
+// Repeater session recording and replay. Sessions are stored as JSONL, one frame per line, with the
+// time since the connection opened so replays can reproduce the original timing.
+
+use super::normalize_remote_address;
+use crate::IntifaceError;
+use futures::{SinkExt, StreamExt};
+use serde::{Deserialize, Serialize};
+use std::{
+  net::SocketAddr,
+  path::{Path, PathBuf},
+  time::{Duration, SystemTime, UNIX_EPOCH},
+};
+use tokio::{
+  fs::{self, File},
+  io::AsyncWriteExt,
+  select,
+  sync::mpsc,
+  time::Instant,
+};
+use tokio_tungstenite::{connect_async, tungstenite::Message};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub(super) enum RepeaterFrameDirection {
+  ClientToServer,
+  ServerToClient,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct RepeaterSessionFrame {
+  elapsed_ms: u64,
+  direction: RepeaterFrameDirection,
+  frame: String,
+}
+
+pub(super) struct RepeaterSessionRecorder {
+  start: Instant,
+  sender: mpsc::UnboundedSender<RepeaterSessionFrame>,
+}
+
+impl RepeaterSessionRecorder {
+  pub(super) async fn new(
+    directory: &Path,
+    client_addr: &SocketAddr,
+  ) -> Result<Self, std::io::Error> {
+    fs::create_dir_all(directory).await?;
+    let timestamp = SystemTime::now()
+      .duration_since(UNIX_EPOCH)
+      .unwrap_or_default()
+      .as_millis();
+    let session_path: PathBuf = directory.join(format!(
+      "repeater-session-{}-{}.jsonl",
+      timestamp,
+      client_addr.port()
+    ));
+    let mut file = File::create(&session_path).await?;
+    info!("Recording repeater session to {:?}", session_path);
+    let (sender, mut receiver) = mpsc::unbounded_channel::<RepeaterSessionFrame>();
+    tokio::spawn(async move {
+      while let Some(frame) = receiver.recv().await {
+        let mut line =
+          serde_json::to_string(&frame).expect("Session frames are always serializable");
+        line.push('\n');
+        if let Err(e) = file.write_all(line.as_bytes()).await {
+          error!(
+            "Cannot write repeater session frame, stopping recording: {:?}",
+            e
+          );
+          return;
+        }
+      }
+      if let Err(e) = file.flush().await {
+        error!("Cannot flush repeater session file: {:?}", e);
+      }
+    });
+    Ok(Self {
+      start: Instant::now(),
+      sender,
+    })
+  }
+
+  pub(super) fn record(&self, direction: RepeaterFrameDirection, msg: &Message) {
+    let Ok(frame) = msg.to_text() else {
+      warn!("Cannot record non-text repeater frame, skipping.");
+      return;
+    };
+    // If the writer task has died, it will already have logged why.
+    let _ = self.sender.send(RepeaterSessionFrame {
+      elapsed_ms: self.start.elapsed().as_millis() as u64,
+      direction,
+      frame: frame.to_owned(),
+    });
+  }
+}
+
+/// Plays the client side of a recorded repeater session against the server at `server_address`,
+/// using the original frame timing. Server replies are logged but otherwise ignored.
+pub async fn replay_repeater_session(
+  session_path: &str,
+  server_address: &str,
+) -> Result<(), IntifaceError> {
+  let session = fs::read_to_string(session_path).await.map_err(|e| {
+    IntifaceError::new(&format!(
+      "Error opening repeater session {}: {:?}",
+      session_path, e
+    ))
+  })?;
+  let frames = session
+    .lines()
+    .filter(|line| !line.trim().is_empty())
+    .map(serde_json::from_str::<RepeaterSessionFrame>)
+    .collect::<Result<Vec<_>, _>>()
+    .map_err(|e| {
+      IntifaceError::new(&format!(
+        "Error reading repeater session {}: {:?}",
+        session_path, e
+      ))
+    })?;
+
+  let server_address = normalize_remote_address(server_address);
+  info!(
+    "Replaying session {} against {}",
+    session_path, server_address
+  );
+  let (ws_stream, _) = connect_async(&server_address).await.map_err(|e| {
+    IntifaceError::new(&format!(
+      "Cannot connect to {} for replay: {:?}",
+      server_address, e
+    ))
+  })?;
+  let (mut server_write, server_read) = ws_stream.split();
+
+  let start = Instant::now();
+  let replay_fut = async {
+    for frame in frames
+      .into_iter()
+      .filter(|frame| frame.direction == RepeaterFrameDirection::ClientToServer)
+    {
+      tokio::time::sleep_until(start + Duration::from_millis(frame.elapsed_ms)).await;
+      debug!("Replaying frame: {}", frame.frame);
+      server_write
+        .send(Message::text(frame.frame))
+        .await
+        .map_err(|e| IntifaceError::new(&format!("Cannot send replayed frame: {:?}", e)))?;
+    }
+    // Give the server a moment to reply to the last few frames before we hang up.
+    tokio::time::sleep(Duration::from_secs(1)).await;
+    Ok(())
+  };
+  let reply_fut = server_read.for_each(|msg| async move {
+    match msg {
+      Ok(msg) => info!("Replay server frame: {}", msg),
+      Err(e) => error!("Error reading from replay server: {:?}", e),
+    }
+  });
+
+  let result = select! {
+    result = replay_fut => result,
+    _ = reply_fut => Err(IntifaceError::new("Server closed connection during replay")),
+  };
+  info!("Session replay finished");
+  result
+}
@@ -0,0 +1,57 @@
+use crate::IntifaceError;
+use std::{fs::File, io::BufReader, sync::Arc};
+use tokio_rustls::{
+  rustls::{crypto::ring, ServerConfig},
+  TlsAcceptor,
+};
+
+// Builds a TLS acceptor for downstream repeater clients from PEM encoded certificate chain and
+// private key files.
+pub(super) fn load_tls_acceptor(
+  cert_path: &str,
+  key_path: &str,
+) -> Result<TlsAcceptor, IntifaceError> {
+  let cert_file = File::open(cert_path).map_err(|e| {
+    IntifaceError::new(&format!(
+      "Error opening repeater TLS certificate {}: {:?}",
+      cert_path, e
+    ))
+  })?;
+  let certs = rustls_pemfile::certs(&mut BufReader::new(cert_file))
+    .collect::<Result<Vec<_>, _>>()
+    .map_err(|e| {
+      IntifaceError::new(&format!(
+        "Error reading repeater TLS certificate {}: {:?}",
+        cert_path, e
+      ))
+    })?;
+  let key_file = File::open(key_path).map_err(|e| {
+    IntifaceError::new(&format!(
+      "Error opening repeater TLS private key {}: {:?}",
+      key_path, e
+    ))
+  })?;
+  let key = rustls_pemfile::private_key(&mut BufReader::new(key_file))
+    .map_err(|e| {
+      IntifaceError::new(&format!(
+        "Error reading repeater TLS private key {}: {:?}",
+        key_path, e
+      ))
+    })?
+    .ok_or_else(|| {
+      IntifaceError::new(&format!(
+        "No private key found in repeater TLS key file {}",
+        key_path
+      ))
+    })?;
+  let config = ServerConfig::builder_with_provider(Arc::new(ring::default_provider()))
+    .with_safe_default_protocol_versions()
+    .and_then(|builder| builder.with_no_client_auth().with_single_cert(certs, key))
+    .map_err(|e| {
+      IntifaceError::new(&format!(
+        "Error setting up repeater TLS configuration: {:?}",
+        e
+      ))
+    })?;
+  Ok(TlsAcceptor::from(Arc::new(config)))
+}
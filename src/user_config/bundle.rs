@@ -0,0 +1,390 @@
+// User config bundles, plus migration of older user config formats. A bundle wraps a current format
+// user config with some metadata about where it came from, so it can be versioned independently of
+// buttplug's config file versions.
+//
+// The v2 -> v3 user config change (Buttplug v8) moved per device settings under a full device
+// definition, so migrating devices needs the base device config to fill in names and features.
+
+use crate::IntifaceError;
+use buttplug::{
+  server::device::configuration::{
+    DeviceConfigurationManager, UserDeviceCustomization, UserDeviceIdentifier,
+  },
+  util::device_configuration::{load_protocol_configs, save_user_config},
+};
+use getset::{CopyGetters, Getters};
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Map, Value};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+const VERSION: &str = env!("CARGO_PKG_VERSION");
+const USER_CONFIG_BUNDLE_VERSION: u32 = 1;
+const CURRENT_USER_CONFIG_MAJOR_VERSION: u64 = 3;
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct UserConfigBundle {
+  bundle_version: u32,
+  engine_version: String,
+  exported_at: u64,
+  user_config: Value,
+}
+
+/// What happened while importing a user config.
+#[derive(Debug, Clone, Default, Serialize, Deserialize, Getters, CopyGetters)]
+pub struct UserConfigImportReport {
+  /// Major version of the user config that was imported.
+  #[getset(get_copy = "pub")]
+  source_version: u64,
+  #[getset(get_copy = "pub")]
+  from_bundle: bool,
+  /// True if the config had to be converted from an older format.
+  #[getset(get_copy = "pub")]
+  migrated: bool,
+  #[getset(get_copy = "pub")]
+  devices_imported: usize,
+  #[getset(get_copy = "pub")]
+  protocols_imported: usize,
+  /// Anything that couldn't be converted, and was dropped.
+  #[getset(get = "pub")]
+  warnings: Vec<String>,
+}
+
+fn config_major_version(config: &Value) -> Option<u64> {
+  config.get("version")?.get("major")?.as_u64()
+}
+
+/// True if the user config is a bundle or older format file, and needs [import_user_config] to be
+/// loaded.
+pub(crate) fn needs_import(user_config_json: &str) -> bool {
+  match serde_json::from_str::<Value>(user_config_json) {
+    Ok(config) => {
+      config.get("bundle_version").is_some()
+        || config_major_version(&config) != Some(CURRENT_USER_CONFIG_MAJOR_VERSION)
+    }
+    // Let the regular config loader complain about it.
+    Err(_) => false,
+  }
+}
+
+fn base_dcm(
+  device_config_json: &Option<String>,
+) -> Result<DeviceConfigurationManager, IntifaceError> {
+  load_protocol_configs(device_config_json, &None, false)
+    .and_then(|mut builder| builder.finish())
+    .map_err(|e| IntifaceError::new(&format!("Cannot load base device configuration: {}", e)))
+}
+
+// Loads a current format user config on top of the base config, so we know it's valid, and get it
+// back in normalized form.
+fn load_current(
+  user_config: &Value,
+  device_config_json: &Option<String>,
+) -> Result<DeviceConfigurationManager, IntifaceError> {
+  load_protocol_configs(device_config_json, &Some(user_config.to_string()), false)
+    .and_then(|mut builder| builder.finish())
+    .map_err(|e| IntifaceError::new(&format!("Invalid user configuration: {}", e)))
+}
+
+fn migrate_v2(
+  user_config: &Value,
+  device_config_json: &Option<String>,
+  report: &mut UserConfigImportReport,
+) -> Result<Value, IntifaceError> {
+  let empty = Map::new();
+  let user_configs = user_config
+    .get("user-configs")
+    .and_then(|c| c.as_object())
+    .unwrap_or(&empty);
+
+  // v2 specifiers were a map of communication type to specifier, v3 makes that a list.
+  let mut protocols = Map::new();
+  if let Some(specifiers) = user_configs.get("specifiers").and_then(|s| s.as_object()) {
+    for (protocol, specifier) in specifiers {
+      let communication: Vec<Value> = specifier
+        .as_object()
+        .map(|specifier| {
+          specifier
+            .iter()
+            .map(|(comm_type, definition)| json!({ comm_type: definition }))
+            .collect()
+        })
+        .unwrap_or_default();
+      let definition = json!({ "communication": communication });
+      // Check each protocol on its own, so one bad specifier doesn't sink the whole import.
+      let test_config = json!({
+        "version": { "major": CURRENT_USER_CONFIG_MAJOR_VERSION, "minor": 0 },
+        "user-configs": { "protocols": { protocol: definition.clone() } }
+      });
+      match load_current(&test_config, device_config_json) {
+        Ok(_) => {
+          protocols.insert(protocol.clone(), definition);
+        }
+        Err(e) => report.warnings.push(format!(
+          "Could not convert communication settings for protocol {}: {}",
+          protocol, e
+        )),
+      }
+    }
+  }
+
+  let base_dcm = base_dcm(device_config_json)?;
+  let mut devices = vec![];
+  for device in user_configs
+    .get("devices")
+    .and_then(|d| d.as_array())
+    .cloned()
+    .unwrap_or_default()
+  {
+    let Some(identifier) = device
+      .get("identifier")
+      .and_then(|i| serde_json::from_value::<UserDeviceIdentifier>(i.clone()).ok())
+    else {
+      report.warnings.push(format!(
+        "Skipping device with invalid identifier: {}",
+        device
+      ));
+      continue;
+    };
+    let config = device.get("config").cloned().unwrap_or_default();
+    // v3 user configs carry a full device definition, so start from what the base config has.
+    let Some(mut definition) = base_dcm.device_definition(&identifier, &[]) else {
+      report.warnings.push(format!(
+        "No device definition for {:?} in the device config, its settings were dropped",
+        identifier
+      ));
+      continue;
+    };
+    if config.get("messages").is_some() {
+      report.warnings.push(format!(
+        "Per device message settings for {:?} cannot be converted, and were dropped",
+        identifier
+      ));
+    }
+    definition.set_user_config(UserDeviceCustomization::new(
+      &config
+        .get("display-name")
+        .and_then(|n| n.as_str())
+        .map(|n| n.to_owned()),
+      config
+        .get("allow")
+        .and_then(|a| a.as_bool())
+        .unwrap_or(false),
+      config
+        .get("deny")
+        .and_then(|d| d.as_bool())
+        .unwrap_or(false),
+      config
+        .get("index")
+        .and_then(|i| i.as_u64())
+        .map(|i| i as u32)
+        .unwrap_or(definition.user_config().index()),
+    ));
+    devices.push(json!({ "identifier": identifier, "config": definition }));
+  }
+
+  Ok(json!({
+    "version": { "major": CURRENT_USER_CONFIG_MAJOR_VERSION, "minor": 0 },
+    "user-configs": { "protocols": protocols, "devices": devices }
+  }))
+}
+
+/// Imports a user config bundle, or a bare user config file of the current or an older format,
+/// returning a current format user config. Older formats are migrated, using the device config
+/// (or the built in one if None) to fill in device definitions. Anything that can't be converted is
+/// listed in the report.
+pub fn import_user_config(
+  contents: &str,
+  device_config_json: &Option<String>,
+) -> Result<(String, UserConfigImportReport), IntifaceError> {
+  let mut report = UserConfigImportReport::default();
+  let mut config: Value = serde_json::from_str(contents)
+    .map_err(|e| IntifaceError::new(&format!("User config is not valid JSON: {}", e)))?;
+
+  if config.get("bundle_version").is_some() {
+    let bundle: UserConfigBundle = serde_json::from_value(config)
+      .map_err(|e| IntifaceError::new(&format!("Invalid user config bundle: {}", e)))?;
+    if bundle.bundle_version > USER_CONFIG_BUNDLE_VERSION {
+      return Err(IntifaceError::new(&format!(
+        "User config bundle version {} is newer than this engine supports ({})",
+        bundle.bundle_version, USER_CONFIG_BUNDLE_VERSION
+      )));
+    }
+    report.from_bundle = true;
+    config = bundle.user_config;
+  }
+
+  report.source_version = config_major_version(&config)
+    .ok_or_else(|| IntifaceError::new("User config has no version, cannot import it"))?;
+  let config = match report.source_version {
+    CURRENT_USER_CONFIG_MAJOR_VERSION => config,
+    2 => {
+      report.migrated = true;
+      migrate_v2(&config, device_config_json, &mut report)?
+    }
+    version => {
+      return Err(IntifaceError::new(&format!(
+        "User config version {} is not supported",
+        version
+      )))
+    }
+  };
+
+  let dcm = load_current(&config, device_config_json)?;
+  report.devices_imported = dcm.user_device_definitions().len();
+  report.protocols_imported = dcm.user_communication_specifiers().len();
+  let user_config = save_user_config(&dcm)
+    .map_err(|e| IntifaceError::new(&format!("Cannot serialize user config: {}", e)))?;
+  Ok((user_config, report))
+}
+
+/// Wraps a user config (anything [import_user_config] accepts, such as the output of
+/// `save_user_config`) in a versioned bundle.
+pub fn export_user_config_bundle(
+  contents: &str,
+  device_config_json: &Option<String>,
+) -> Result<(String, UserConfigImportReport), IntifaceError> {
+  let (user_config, report) = import_user_config(contents, device_config_json)?;
+  let bundle = UserConfigBundle {
+    bundle_version: USER_CONFIG_BUNDLE_VERSION,
+    engine_version: VERSION.to_owned(),
+    exported_at: SystemTime::now()
+      .duration_since(UNIX_EPOCH)
+      .unwrap_or_default()
+      .as_secs(),
+    user_config: serde_json::from_str(&user_config)
+      .map_err(|e| IntifaceError::new(&format!("Cannot serialize user config: {}", e)))?,
+  };
+  let bundle = serde_json::to_string_pretty(&bundle)
+    .map_err(|e| IntifaceError::new(&format!("Cannot serialize user config bundle: {}", e)))?;
+  Ok((bundle, report))
+}
+
+#[cfg(test)]
+mod test {
+  use super::*;
+
+  // A v2 (Buttplug v7) user config, with one of everything the migration has to deal with.
+  const V2_USER_CONFIG: &str = r#"{
+    "version": { "major": 2, "minor": 0 },
+    "user-configs": {
+      "specifiers": {
+        "lovense": {
+          "serial": { "port": "COM7", "baud-rate": 115200, "data-bits": 8, "parity": "N", "stop-bits": 1 }
+        }
+      },
+      "devices": [
+        {
+          "identifier": { "address": "aa:bb:cc:dd:ee:ff", "protocol": "lovense", "identifier": "P" },
+          "config": { "display-name": "Living Room", "allow": true, "index": 5 }
+        },
+        {
+          "identifier": { "address": "11:22:33:44:55:66", "protocol": "lovense", "identifier": "L" },
+          "config": { "deny": true, "messages": { "VibrateCmd": { "FeatureCount": 1 } } }
+        },
+        {
+          "identifier": { "address": "gone", "protocol": "not-a-real-protocol", "identifier": null },
+          "config": { "allow": true }
+        }
+      ]
+    }
+  }"#;
+
+  // Saved user configs list devices in whatever order the DCM keeps them in, so sort them for
+  // comparisons.
+  fn sort_devices(mut user_config: Value) -> Value {
+    if let Some(devices) = user_config["user-configs"]["devices"].as_array_mut() {
+      devices.sort_by_key(|device| device["identifier"]["address"].to_string());
+    }
+    user_config
+  }
+
+  fn user_config_dcm(user_config: &str) -> DeviceConfigurationManager {
+    load_current(&serde_json::from_str(user_config).unwrap(), &None).unwrap()
+  }
+
+  #[test]
+  fn test_needs_import() {
+    assert!(needs_import(V2_USER_CONFIG));
+    assert!(needs_import(r#"{ "bundle_version": 1 }"#));
+    assert!(!needs_import(
+      r#"{ "version": { "major": 3, "minor": 0 }, "user-configs": {} }"#
+    ));
+    assert!(!needs_import("not json"));
+  }
+
+  #[test]
+  fn test_migrate_v2() {
+    let (user_config, report) = import_user_config(V2_USER_CONFIG, &None).unwrap();
+    assert_eq!(report.source_version(), 2);
+    assert!(report.migrated());
+    assert!(!report.from_bundle());
+    assert_eq!(report.devices_imported(), 2);
+    assert_eq!(report.protocols_imported(), 1);
+    assert_eq!(report.warnings().len(), 2, "{:?}", report.warnings());
+    assert!(report
+      .warnings()
+      .iter()
+      .any(|w| w.starts_with("Per device message settings") && w.contains("11:22:33:44:55:66")));
+    assert!(report
+      .warnings()
+      .iter()
+      .any(|w| w.starts_with("No device definition") && w.contains("not-a-real-protocol")));
+
+    let dcm = user_config_dcm(&user_config);
+    assert!(dcm.user_communication_specifiers().contains_key("lovense"));
+    let base_dcm = base_dcm(&None).unwrap();
+    let allowed = UserDeviceIdentifier::new("aa:bb:cc:dd:ee:ff", "lovense", &Some("P".to_owned()));
+    let definition = dcm.user_device_definitions().get(&allowed).unwrap().clone();
+    assert_eq!(
+      definition.name(),
+      base_dcm.device_definition(&allowed, &[]).unwrap().name()
+    );
+    assert_eq!(
+      definition.user_config().display_name(),
+      &Some("Living Room".to_owned())
+    );
+    assert!(definition.user_config().allow());
+    assert!(!definition.user_config().deny());
+    assert_eq!(definition.user_config().index(), 5);
+    let denied = UserDeviceIdentifier::new("11:22:33:44:55:66", "lovense", &Some("L".to_owned()));
+    let definition = dcm.user_device_definitions().get(&denied).unwrap().clone();
+    assert!(definition.user_config().deny());
+    assert!(!definition.user_config().allow());
+  }
+
+  #[test]
+  fn test_bundle_round_trip() {
+    let (bundle, export_report) = export_user_config_bundle(V2_USER_CONFIG, &None).unwrap();
+    assert!(export_report.migrated());
+    let bundle_value: Value = serde_json::from_str(&bundle).unwrap();
+    assert_eq!(bundle_value["bundle_version"], USER_CONFIG_BUNDLE_VERSION);
+    assert_eq!(bundle_value["engine_version"], VERSION);
+
+    let (user_config, report) = import_user_config(&bundle, &None).unwrap();
+    assert!(report.from_bundle());
+    assert!(!report.migrated());
+    assert_eq!(report.source_version(), CURRENT_USER_CONFIG_MAJOR_VERSION);
+    assert_eq!(report.devices_imported(), 2);
+    assert_eq!(report.protocols_imported(), 1);
+    assert!(report.warnings().is_empty());
+    assert_eq!(
+      sort_devices(serde_json::from_str(&user_config).unwrap()),
+      sort_devices(bundle_value["user_config"].clone())
+    );
+  }
+
+  #[test]
+  fn test_newer_bundle_version_rejected() {
+    let (bundle, _) = export_user_config_bundle(V2_USER_CONFIG, &None).unwrap();
+    let mut bundle: Value = serde_json::from_str(&bundle).unwrap();
+    bundle["bundle_version"] = json!(USER_CONFIG_BUNDLE_VERSION + 1);
+    let error = import_user_config(&bundle.to_string(), &None).unwrap_err();
+    assert!(
+      error
+        .to_string()
+        .contains("is newer than this engine supports"),
+      "{}",
+      error
+    );
+  }
+}
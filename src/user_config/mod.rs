@@ -0,0 +1,586 @@
+// User device config loading, editing and persistence. Saves are requested whenever something that
+// could change the user config happens, debounced, and written atomically (temp file plus rename)
+// with a few rotating backups of the previous versions.
+
+mod bundle;
+
+pub use bundle::{export_user_config_bundle, import_user_config, UserConfigImportReport};
+
+use crate::{
+  frontend::{EngineMessage, Frontend, IntifaceMessage},
+  options::EngineOptions,
+  IntifaceError,
+};
+use buttplug::{
+  core::message::{DeviceFeature, DeviceFeatureActuator},
+  server::device::configuration::{
+    DeviceConfigurationManager, UserDeviceCustomization, UserDeviceDefinition, UserDeviceIdentifier,
+  },
+  util::device_configuration::save_user_config,
+};
+use getset::Getters;
+use serde::{Deserialize, Serialize};
+use std::{
+  path::{Path, PathBuf},
+  sync::Arc,
+  time::Duration,
+};
+use tokio::{
+  fs,
+  io::AsyncWriteExt,
+  select,
+  sync::{broadcast, Notify},
+  task::JoinHandle,
+};
+use tokio_util::sync::CancellationToken;
+
+const USER_CONFIG_SAVE_DEBOUNCE: Duration = Duration::from_secs(1);
+// Saves still happen this long after the first request, even if requests keep coming in.
+const USER_CONFIG_SAVE_MAX_DELAY: Duration = Duration::from_secs(10);
+pub(crate) const DEFAULT_USER_CONFIG_BACKUP_COUNT: usize = 3;
+
+/// Figures out which user config JSON to load. JSON given directly in the options wins, otherwise
+/// it's read from the user config path if that exists. Bundles and older format configs are
+/// converted to the current format, which gets written back on the next save.
+pub(crate) async fn load_user_config_json(
+  options: &EngineOptions,
+) -> Result<Option<String>, IntifaceError> {
+  if let Some(json) = options.user_device_config_json() {
+    return upgrade_user_config_json(json, options.device_config_json()).map(Some);
+  }
+  let Some(path) = options.user_device_config_path() else {
+    return Ok(None);
+  };
+  match fs::read_to_string(path).await {
+    Ok(json) => {
+      info!("Loading user config from {}", path);
+      upgrade_user_config_json(&json, options.device_config_json()).map(Some)
+    }
+    Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+      info!(
+        "User config {} does not exist yet, it will be created.",
+        path
+      );
+      Ok(None)
+    }
+    Err(e) => Err(IntifaceError::new(&format!(
+      "Error opening user device configuration {}: {:?}",
+      path, e
+    ))),
+  }
+}
+
+/// Converts bundles and older format user configs to the current format, logging anything that
+/// couldn't be converted. Current format configs are returned as is.
+pub(crate) fn upgrade_user_config_json(
+  json: &str,
+  device_config_json: &Option<String>,
+) -> Result<String, IntifaceError> {
+  if !bundle::needs_import(json) {
+    return Ok(json.to_owned());
+  }
+  let (json, report) = import_user_config(json, device_config_json)?;
+  info!(
+    "Imported user config (version {}, bundled: {}), {} devices and {} protocols",
+    report.source_version(),
+    report.from_bundle(),
+    report.devices_imported(),
+    report.protocols_imported()
+  );
+  for warning in report.warnings() {
+    warn!("User config import: {}", warning);
+  }
+  Ok(json)
+}
+
+/// Imports a user config bundle or older format user config from `input` (see
+/// [import_user_config]), and writes it to `output` in the current format. If `output` already
+/// exists, it's kept as a backup.
+pub async fn import_user_config_file(
+  input: &str,
+  output: &str,
+  device_config_json: &Option<String>,
+) -> Result<UserConfigImportReport, IntifaceError> {
+  let contents = fs::read_to_string(input)
+    .await
+    .map_err(|e| IntifaceError::new(&format!("Error opening user config {}: {:?}", input, e)))?;
+  let (config, report) = import_user_config(&contents, device_config_json)?;
+  write_config_file(Path::new(output), &config, DEFAULT_USER_CONFIG_BACKUP_COUNT)
+    .await
+    .map_err(|e| IntifaceError::new(&format!("Cannot write user config to {}: {:?}", output, e)))?;
+  Ok(report)
+}
+
+/// Handle for requesting user config saves. Requests that come in close together are collapsed into
+/// a single save.
+#[derive(Clone)]
+pub(crate) struct UserConfigSaver {
+  notify: Arc<Notify>,
+}
+
+impl UserConfigSaver {
+  pub(crate) fn request_save(&self) {
+    self.notify.notify_one();
+  }
+}
+
+pub(crate) struct UserConfigPersistence {
+  saver: UserConfigSaver,
+  stop_token: CancellationToken,
+  task: JoinHandle<()>,
+}
+
+impl UserConfigPersistence {
+  pub(crate) fn new(
+    path: &str,
+    backup_count: usize,
+    dcm: Arc<DeviceConfigurationManager>,
+    frontend: Option<Arc<dyn Frontend>>,
+  ) -> Self {
+    let saver = UserConfigSaver {
+      notify: Arc::new(Notify::new()),
+    };
+    let stop_token = CancellationToken::new();
+    let writer = UserConfigWriter {
+      path: PathBuf::from(path),
+      backup_count,
+      dcm,
+      last_saved: None,
+    };
+    // Make sure the config file exists (and is in the current format) from the start, not just once
+    // something changes.
+    saver.request_save();
+    let task = tokio::spawn(persistence_loop(
+      writer,
+      saver.notify.clone(),
+      stop_token.clone(),
+      frontend,
+    ));
+    Self {
+      saver,
+      stop_token,
+      task,
+    }
+  }
+
+  pub(crate) fn saver(&self) -> UserConfigSaver {
+    self.saver.clone()
+  }
+
+  /// Stops the persistence task, waiting for it to do a final save.
+  pub(crate) async fn shutdown(self) {
+    self.stop_token.cancel();
+    if let Err(e) = self.task.await {
+      error!("User config persistence task failed: {:?}", e);
+    }
+  }
+}
+
+struct UserConfigWriter {
+  path: PathBuf,
+  backup_count: usize,
+  dcm: Arc<DeviceConfigurationManager>,
+  last_saved: Option<String>,
+}
+
+// Writes a config file atomically (temp file plus rename), keeping `backup_count` previous versions.
+async fn write_config_file(
+  path: &Path,
+  config: &str,
+  backup_count: usize,
+) -> Result<(), std::io::Error> {
+  if let Some(parent) = path.parent().filter(|p| !p.as_os_str().is_empty()) {
+    fs::create_dir_all(parent).await?;
+  }
+  let mut temp_path = path.as_os_str().to_owned();
+  temp_path.push(".tmp");
+  let temp_path = PathBuf::from(temp_path);
+  let mut file = fs::File::create(&temp_path).await?;
+  file.write_all(config.as_bytes()).await?;
+  file.sync_all().await?;
+  drop(file);
+
+  // Copy instead of renaming the current file into the backups, so there's always a config at the
+  // real path.
+  if backup_count > 0 && fs::try_exists(path).await? {
+    for generation in (1..backup_count).rev() {
+      let from = backup_path(path, generation);
+      if fs::try_exists(&from).await? {
+        fs::rename(&from, backup_path(path, generation + 1)).await?;
+      }
+    }
+    fs::copy(path, backup_path(path, 1)).await?;
+  }
+  fs::rename(&temp_path, &path).await
+}
+
+fn backup_path(path: &Path, generation: usize) -> PathBuf {
+  let mut backup = path.as_os_str().to_owned();
+  backup.push(format!(".bak.{}", generation));
+  PathBuf::from(backup)
+}
+
+impl UserConfigWriter {
+  async fn save(&mut self) -> Result<(), IntifaceError> {
+    let config = save_user_config(&self.dcm)
+      .map_err(|e| IntifaceError::new(&format!("Cannot serialize user config: {}", e)))?;
+    if self.last_saved.is_none() {
+      self.last_saved = fs::read_to_string(&self.path).await.ok();
+    }
+    // Compare parsed JSON, so a hand formatted file doesn't get rewritten just for formatting.
+    let parse = |json: &str| serde_json::from_str::<serde_json::Value>(json).ok();
+    if self
+      .last_saved
+      .as_deref()
+      .is_some_and(|saved| saved == config || parse(saved) == parse(&config))
+    {
+      debug!("User config unchanged, skipping save.");
+      return Ok(());
+    }
+    write_config_file(&self.path, &config, self.backup_count)
+      .await
+      .map_err(|e| {
+        IntifaceError::new(&format!(
+          "Cannot write user config to {:?}: {:?}",
+          self.path, e
+        ))
+      })?;
+    info!("Saved user config to {:?}", self.path);
+    self.last_saved = Some(config);
+    Ok(())
+  }
+}
+
+// Waits for things to settle after a save request, so a burst of changes only causes one write. A
+// steady stream of changes can't hold off the save for longer than USER_CONFIG_SAVE_MAX_DELAY.
+async fn debounce_save(notify: &Notify, stop_token: &CancellationToken) {
+  let deadline = tokio::time::Instant::now() + USER_CONFIG_SAVE_MAX_DELAY;
+  loop {
+    select! {
+      _ = notify.notified() => continue,
+      _ = tokio::time::sleep(USER_CONFIG_SAVE_DEBOUNCE) => break,
+      _ = tokio::time::sleep_until(deadline) => break,
+      _ = stop_token.cancelled() => break,
+    }
+  }
+}
+
+async fn persistence_loop(
+  mut writer: UserConfigWriter,
+  notify: Arc<Notify>,
+  stop_token: CancellationToken,
+  frontend: Option<Arc<dyn Frontend>>,
+) {
+  let report_result = |result: Result<(), IntifaceError>| {
+    let frontend = frontend.clone();
+    async move {
+      if let Err(e) = result {
+        error!("{}", e);
+        if let Some(frontend) = frontend {
+          frontend
+            .send(EngineMessage::UserConfigSaveFailed {
+              error: e.to_string(),
+            })
+            .await;
+        }
+      }
+    }
+  };
+  loop {
+    select! {
+      _ = notify.notified() => {}
+      _ = stop_token.cancelled() => break,
+    }
+    debounce_save(&notify, &stop_token).await;
+    if stop_token.is_cancelled() {
+      break;
+    }
+    report_result(writer.save().await).await;
+  }
+  info!("Saving user config before shutdown.");
+  report_result(writer.save().await).await;
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, Getters)]
+pub struct UserDeviceConfigEntry {
+  #[getset(get = "pub")]
+  identifier: UserDeviceIdentifier,
+  #[getset(get = "pub")]
+  config: UserDeviceDefinition,
+}
+
+impl UserDeviceConfigEntry {
+  pub fn new(identifier: &UserDeviceIdentifier, config: &UserDeviceDefinition) -> Self {
+    Self {
+      identifier: identifier.clone(),
+      config: config.clone(),
+    }
+  }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct UserDeviceFeatureStepLimit {
+  pub feature_index: usize,
+  pub min: u32,
+  pub max: u32,
+}
+
+/// Changes to make to an existing user device config entry. Anything left unset is unchanged.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct UserDeviceConfigEdit {
+  /// New display name. An empty string clears the display name.
+  pub display_name: Option<String>,
+  pub allow: Option<bool>,
+  pub deny: Option<bool>,
+  /// Device index to reserve for this device.
+  pub index: Option<u32>,
+  /// Step limits for actuator features, which have to fall within the feature's step range.
+  pub feature_step_limits: Vec<UserDeviceFeatureStepLimit>,
+}
+
+fn check_index_available(
+  dcm: &DeviceConfigurationManager,
+  identifier: &UserDeviceIdentifier,
+  index: u32,
+) -> Result<(), IntifaceError> {
+  if dcm
+    .user_device_definitions()
+    .iter()
+    .any(|kv| kv.key() != identifier && kv.value().user_config().index() == index)
+  {
+    Err(IntifaceError::new(&format!(
+      "Device index {} is already reserved by another device",
+      index
+    )))
+  } else {
+    Ok(())
+  }
+}
+
+fn apply_edit(
+  definition: &UserDeviceDefinition,
+  edit: &UserDeviceConfigEdit,
+) -> Result<UserDeviceDefinition, IntifaceError> {
+  let mut definition = definition.clone();
+  let user_config = definition.user_config();
+  let display_name = match &edit.display_name {
+    Some(name) if name.is_empty() => None,
+    Some(name) => Some(name.clone()),
+    None => user_config.display_name().clone(),
+  };
+  let allow = edit.allow.unwrap_or(user_config.allow());
+  let deny = edit.deny.unwrap_or(user_config.deny());
+  if allow && deny {
+    return Err(IntifaceError::new(
+      "A device cannot be on both the allow and deny lists",
+    ));
+  }
+  let index = edit.index.unwrap_or(user_config.index());
+  definition.set_user_config(UserDeviceCustomization::new(
+    &display_name,
+    allow,
+    deny,
+    index,
+  ));
+
+  for limit in &edit.feature_step_limits {
+    let feature = definition
+      .features()
+      .get(limit.feature_index)
+      .ok_or_else(|| IntifaceError::new(&format!("Device has no feature {}", limit.feature_index)))?
+      .clone();
+    let actuator = feature.actuator().as_ref().ok_or_else(|| {
+      IntifaceError::new(&format!(
+        "Feature {} is not an actuator, it has no step limit",
+        limit.feature_index
+      ))
+    })?;
+    let range = actuator.step_range();
+    if limit.min > limit.max || limit.min < *range.start() || limit.max > *range.end() {
+      return Err(IntifaceError::new(&format!(
+        "Step limit {}-{} for feature {} must be within its step range {}-{}",
+        limit.min,
+        limit.max,
+        limit.feature_index,
+        range.start(),
+        range.end()
+      )));
+    }
+    let actuator = DeviceFeatureActuator::new(range, &(limit.min..=limit.max), actuator.messages());
+    definition.features_mut()[limit.feature_index] = DeviceFeature::new(
+      feature.description(),
+      *feature.feature_type(),
+      &Some(actuator),
+      feature.sensor(),
+    );
+  }
+  Ok(definition)
+}
+
+// Applies a user config command to the DCM, returning the message to send back to the frontend, and
+// whether the user config changed. Returns None for messages that aren't user config commands.
+fn handle_user_config_command(
+  dcm: &DeviceConfigurationManager,
+  message: &IntifaceMessage,
+) -> Option<(EngineMessage, bool)> {
+  let result = match message {
+    IntifaceMessage::ListUserDeviceConfigs {} => {
+      let mut configs: Vec<UserDeviceConfigEntry> = dcm
+        .user_device_definitions()
+        .iter()
+        .map(|kv| UserDeviceConfigEntry::new(kv.key(), kv.value()))
+        .collect();
+      configs.sort_by_key(|entry| entry.config.user_config().index());
+      Ok((EngineMessage::UserDeviceConfigs { configs }, false))
+    }
+    IntifaceMessage::AddUserDeviceConfig { identifier, config } => {
+      if dcm.user_device_definitions().contains_key(identifier) {
+        Err(IntifaceError::new(
+          "A user config already exists for this device, edit it instead",
+        ))
+      } else {
+        check_index_available(dcm, identifier, config.user_config().index())
+          .and_then(|_| {
+            dcm
+              .add_user_device_definition(identifier, config)
+              .map_err(|e| IntifaceError::new(&e.to_string()))
+          })
+          .map(|_| {
+            (
+              EngineMessage::UserDeviceConfigUpdated {
+                identifier: identifier.clone(),
+                config: config.clone(),
+              },
+              true,
+            )
+          })
+      }
+    }
+    IntifaceMessage::EditUserDeviceConfig { identifier, edit } => {
+      // Clone out of the map before writing back to it, so we're not holding a DashMap ref.
+      let existing = dcm
+        .user_device_definitions()
+        .get(identifier)
+        .map(|kv| kv.value().clone());
+      match existing {
+        None => Err(IntifaceError::new("No user config exists for this device")),
+        Some(existing) => apply_edit(&existing, edit).and_then(|config| {
+          check_index_available(dcm, identifier, config.user_config().index())?;
+          dcm
+            .add_user_device_definition(identifier, &config)
+            .map_err(|e| IntifaceError::new(&e.to_string()))?;
+          Ok((
+            EngineMessage::UserDeviceConfigUpdated {
+              identifier: identifier.clone(),
+              config,
+            },
+            true,
+          ))
+        }),
+      }
+    }
+    IntifaceMessage::RemoveUserDeviceConfig { identifier } => {
+      if dcm.user_device_definitions().contains_key(identifier) {
+        dcm.remove_user_device_definition(identifier);
+        Ok((
+          EngineMessage::UserDeviceConfigRemoved {
+            identifier: identifier.clone(),
+          },
+          true,
+        ))
+      } else {
+        Err(IntifaceError::new("No user config exists for this device"))
+      }
+    }
+    _ => return None,
+  };
+  Some(result.unwrap_or_else(|e| {
+    (
+      EngineMessage::UserDeviceConfigError {
+        error: e.to_string(),
+      },
+      false,
+    )
+  }))
+}
+
+/// Handles user device config commands from the frontend. Changes are applied to the DCM right away,
+/// so they're used the next time a device connects, and saved via `saver` if we have one.
+pub(crate) async fn user_config_command_loop(
+  dcm: Arc<DeviceConfigurationManager>,
+  frontend: Arc<dyn Frontend>,
+  saver: Option<UserConfigSaver>,
+  stop_token: CancellationToken,
+) {
+  let mut receiver = frontend.event_stream();
+  loop {
+    let message = select! {
+      message = receiver.recv() => match message {
+        Ok(message) => message,
+        Err(broadcast::error::RecvError::Lagged(_)) => continue,
+        Err(broadcast::error::RecvError::Closed) => break,
+      },
+      _ = stop_token.cancelled() => break,
+    };
+    if let Some((reply, changed)) = handle_user_config_command(&dcm, &message) {
+      if changed {
+        if let Some(saver) = &saver {
+          saver.request_save();
+        }
+      }
+      frontend.send(reply).await;
+    }
+  }
+}
+
+#[cfg(test)]
+mod test {
+  use super::*;
+
+  #[tokio::test(start_paused = true)]
+  async fn test_debounce_save_waits_for_quiet() {
+    let notify = Arc::new(Notify::new());
+    let stop_token = CancellationToken::new();
+    let start = tokio::time::Instant::now();
+    let notify_clone = notify.clone();
+    tokio::spawn(async move {
+      for _ in 0..3 {
+        tokio::time::sleep(Duration::from_millis(500)).await;
+        notify_clone.notify_one();
+      }
+    });
+    debounce_save(&notify, &stop_token).await;
+    assert_eq!(
+      start.elapsed(),
+      Duration::from_millis(1500) + USER_CONFIG_SAVE_DEBOUNCE
+    );
+  }
+
+  #[tokio::test(start_paused = true)]
+  async fn test_debounce_save_max_delay() {
+    let notify = Arc::new(Notify::new());
+    let stop_token = CancellationToken::new();
+    let start = tokio::time::Instant::now();
+    let notify_clone = notify.clone();
+    let requests = tokio::spawn(async move {
+      loop {
+        tokio::time::sleep(Duration::from_millis(500)).await;
+        notify_clone.notify_one();
+      }
+    });
+    debounce_save(&notify, &stop_token).await;
+    requests.abort();
+    assert_eq!(start.elapsed(), USER_CONFIG_SAVE_MAX_DELAY);
+  }
+
+  #[tokio::test(start_paused = true)]
+  async fn test_debounce_save_stops_on_cancel() {
+    let notify = Notify::new();
+    let stop_token = CancellationToken::new();
+    stop_token.cancel();
+    let start = tokio::time::Instant::now();
+    debounce_save(&notify, &stop_token).await;
+    assert_eq!(start.elapsed(), Duration::ZERO);
+  }
+}